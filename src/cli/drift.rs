@@ -0,0 +1,324 @@
+use crate::cli::{ClusterArgs, cached_note, diff_node, print_error_details};
+use crate::nix::ClusterConfig;
+use crate::nix::batch::{BatchEvent, BatchNode, PreparedNode, prepare_nodes};
+use crate::nix::diff::NodeStatus;
+use crate::nix::error::DiffError;
+use crate::nix::scheduler::Scheduler;
+use crate::nix::source;
+use crate::utils::ansi_to_rich::strip_ansi;
+use anyhow::Context;
+use clap::Args;
+use futures::{StreamExt, executor};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process::ExitCode;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Exit code returned when at least one node drifted from its configuration.
+pub const EXIT_DRIFT: u8 = 2;
+
+#[derive(Debug, Args)]
+pub struct CheckDriftArgs {
+    #[command(flatten)]
+    pub cluster: ClusterArgs,
+    /// Write a JSON report to this file. Use `-` for stdout
+    #[arg(long)]
+    pub json: Option<PathBuf>,
+    /// Write a JUnit XML report to this file. Use `-` for stdout
+    #[arg(long)]
+    pub junit: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeReport {
+    pub node: String,
+    pub status: NodeStatus,
+    pub duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toplevel: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DriftReport {
+    pub flake: PathBuf,
+    /// Seconds since the unix epoch at which the check started.
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub nodes: Vec<NodeReport>,
+}
+
+impl DriftReport {
+    fn count(&self, status: NodeStatus) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.status == status)
+            .count()
+    }
+
+    pub fn to_junit(&self) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            r#"<testsuites><testsuite name="checkit-drift" tests="{}" failures="{}" errors="{}" time="{:.3}">"#,
+            self.nodes.len(),
+            self.count(NodeStatus::Drifted),
+            self.count(NodeStatus::Error),
+            self.duration_secs,
+        );
+
+        for node in &self.nodes {
+            let _ = write!(
+                xml,
+                r#"  <testcase classname="checkit.drift" name="{}" time="{:.3}""#,
+                xml_escape(&node.node),
+                node.duration_secs,
+            );
+            match node.status {
+                NodeStatus::UpToDate => xml.push_str("/>\n"),
+                NodeStatus::Drifted => {
+                    let _ = writeln!(
+                        xml,
+                        r#"><failure message="node has drifted from its configuration">{}</failure></testcase>"#,
+                        xml_escape(node.diff.as_deref().unwrap_or_default()),
+                    );
+                }
+                NodeStatus::Error => {
+                    let error = node.error.as_deref().unwrap_or_default();
+                    let _ = writeln!(
+                        xml,
+                        r#"><error message="{}">{}</error></testcase>"#,
+                        xml_escape(error.lines().next().unwrap_or_default()),
+                        xml_escape(error),
+                    );
+                }
+            }
+        }
+
+        xml.push_str("</testsuite></testsuites>\n");
+        xml
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .fold(String::with_capacity(text.len()), |mut escaped, c| {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&apos;"),
+                c => escaped.push(c),
+            }
+            escaped
+        })
+}
+
+fn write_report(target: &Path, content: &str) -> anyhow::Result<()> {
+    if target == Path::new("-") {
+        println!("{content}");
+        return Ok(());
+    }
+
+    fs::write(target, content)
+        .with_context(|| format!("Couldn't write report to {}", target.display()))
+}
+
+/// The file a report written to `target` ends up in, however its path is spelled.
+fn report_file(target: &Path) -> anyhow::Result<PathBuf> {
+    if target == Path::new("-") {
+        return Ok(target.to_owned());
+    }
+
+    let path = std::path::absolute(target)
+        .with_context(|| format!("Couldn't resolve {}", target.display()))?;
+    // The report may not exist yet, so only its directory is resolved.
+    Ok(match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => dir
+            .canonicalize()
+            .map_or(path.clone(), |dir| dir.join(name)),
+        _ => path,
+    })
+}
+
+/// Evaluates and builds all nodes at once, so their diffs only have to reach the nodes.
+///
+/// Returns no nodes if that fails, they're then evaluated and built one by one.
+fn prepare_all(
+    config: &ClusterConfig,
+    nodes: &[String],
+    verbose: bool,
+) -> BTreeMap<String, PreparedNode> {
+    let batch_nodes = nodes
+        .iter()
+        .map(|name| BatchNode {
+            name: name.clone(),
+            ip_attr: config.ip_attr.clone(),
+        })
+        .collect();
+    let mut batch = pin!(prepare_nodes(
+        config.clone(),
+        batch_nodes,
+        Scheduler::default().job()
+    ));
+
+    let prepared = executor::block_on(async {
+        while let Some(event) = batch.next().await {
+            match event? {
+                BatchEvent::Stage(stage) => eprintln!("[all nodes] {}", stage.label()),
+                BatchEvent::Log(line) if verbose => eprintln!("[all nodes] {line}"),
+                BatchEvent::Build(event) => {
+                    if let Some(line) = event.text_line().filter(|_| verbose) {
+                        eprintln!("[all nodes] {line}");
+                    }
+                }
+                BatchEvent::Log(_) => {}
+                BatchEvent::Evaluated(node) => match node.result {
+                    Ok(evaluated) if verbose => {
+                        eprintln!(
+                            "[{}] evaluated, at {} (from {})",
+                            node.node,
+                            evaluated.target.host,
+                            evaluated.target.source.label()
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(err) => eprintln!("[{}] failed to evaluate: {err}", node.node),
+                },
+                BatchEvent::Finished(prepared) => return Ok(prepared),
+            }
+        }
+
+        anyhow::bail!("Preparing the nodes ended without a result")
+    });
+
+    prepared.unwrap_or_else(|err| {
+        eprintln!("warning: {err:#}. Evaluating and building the nodes one by one");
+        BTreeMap::new()
+    })
+}
+
+pub fn check_drift(args: CheckDriftArgs, verbose: bool) -> anyhow::Result<ExitCode> {
+    // Both reports in one file or interleaved on stdout can't be parsed anymore.
+    if let (Some(json), Some(junit)) = (&args.json, &args.junit) {
+        anyhow::ensure!(
+            report_file(json)? != report_file(junit)?,
+            "--json and --junit can't both write to {}",
+            json.display()
+        );
+    }
+
+    let config = args.cluster.into_config()?;
+    let nodes =
+        executor::block_on(source::list_nodes(&config)).context("Couldn't fetch cluster nodes")?;
+
+    // The batch evaluation and build is usually most of the check, so it's part of its time.
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    let check_start = Instant::now();
+
+    let mut prepared = prepare_all(&config, &nodes, verbose);
+
+    let nodes = nodes
+        .into_iter()
+        .map(|node| {
+            let node_start = Instant::now();
+            let result = diff_node(
+                config.clone(),
+                node.clone(),
+                verbose,
+                prepared.remove(&node),
+            );
+            let duration_secs = node_start.elapsed().as_secs_f64();
+
+            match result {
+                Ok(diff) => {
+                    let status = if diff.up_to_date {
+                        NodeStatus::UpToDate
+                    } else {
+                        NodeStatus::Drifted
+                    };
+                    eprintln!("[{node}] {status:?}{}", cached_note(&diff));
+                    NodeReport {
+                        node,
+                        status,
+                        duration_secs,
+                        diff: (status == NodeStatus::Drifted).then(|| strip_ansi(&diff.diff)),
+                        system_path: Some(diff.system_path),
+                        toplevel: Some(diff.toplevel),
+                        error: None,
+                        hint: None,
+                    }
+                }
+                Err(err) => {
+                    eprintln!("[{node}] Error: {err:#}");
+                    let diff_err = err.downcast_ref::<DiffError>();
+                    if let Some(diff_err) = diff_err {
+                        print_error_details(diff_err);
+                    }
+                    NodeReport {
+                        node,
+                        status: NodeStatus::Error,
+                        duration_secs,
+                        system_path: None,
+                        toplevel: None,
+                        diff: None,
+                        error: Some(format!("{err:#}")),
+                        hint: diff_err.map(DiffError::hint),
+                    }
+                }
+            }
+        })
+        .collect();
+
+    let report = DriftReport {
+        flake: config.cluster_path,
+        started_at,
+        duration_secs: check_start.elapsed().as_secs_f64(),
+        nodes,
+    };
+
+    let json = serde_json::to_string_pretty(&report).context("Couldn't serialize report")?;
+    match (&args.json, &args.junit) {
+        (None, None) => println!("{json}"),
+        (json_target, junit_target) => {
+            if let Some(target) = json_target {
+                write_report(target, &json)?;
+            }
+            if let Some(target) = junit_target {
+                write_report(target, &report.to_junit())?;
+            }
+        }
+    }
+
+    let drifted = report.count(NodeStatus::Drifted);
+    let errors = report.count(NodeStatus::Error);
+    eprintln!(
+        "{} nodes checked: {} up to date, {drifted} drifted, {errors} failed",
+        report.nodes.len(),
+        report.count(NodeStatus::UpToDate),
+    );
+
+    Ok(if errors > 0 {
+        ExitCode::FAILURE
+    } else if drifted > 0 {
+        ExitCode::from(EXIT_DRIFT)
+    } else {
+        ExitCode::SUCCESS
+    })
+}
@@ -0,0 +1,264 @@
+use crate::cli::drift::CheckDriftArgs;
+use crate::nix::batch::PreparedNode;
+use crate::nix::diff::{DiffEvent, NodeDiff, run_diff};
+use crate::nix::error::DiffError;
+use crate::nix::progress::DiffProgress;
+use crate::nix::scheduler::Scheduler;
+use crate::nix::source::SourceKind;
+use crate::nix::{ClusterConfig, DEFAULT_IP_ATTR, EvalJobs, NixFlags, SshOverrides};
+use crate::utils::format;
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use futures::{StreamExt, executor};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process::ExitCode;
+
+mod drift;
+
+/// Check on your infrastructure. Starts the GUI when no command is given.
+#[derive(Debug, Parser)]
+#[command(name = "checkit", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Print the output of every command run along the way to stderr
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Diff a node's running system against its configuration in the cluster flake
+    Diff(DiffArgs),
+    /// Diff every node of the cluster and report which ones drifted from their configuration.
+    ///
+    /// Exits with 0 when all nodes are up to date, 2 when any node drifted
+    /// and 1 when a node couldn't be checked, even if others drifted.
+    CheckDrift(CheckDriftArgs),
+}
+
+/// Options describing the cluster, shared by all commands.
+#[derive(Debug, Args)]
+pub struct ClusterArgs {
+    /// Path to the cluster's flake.nix, hive.nix or TOML/JSON inventory, or the directory
+    /// containing it or the cluster's NixOS configurations
+    #[arg(long)]
+    pub flake: PathBuf,
+    /// Flake reference to evaluate the nodes from instead of the cluster directory, e.g.
+    /// git+file:///repo?rev=<rev> or path:/repo?dir=cluster. Relative references are
+    /// resolved against the cluster directory
+    #[arg(long, default_value = "")]
+    pub flake_ref: String,
+    /// How the cluster defines its nodes, detected from the cluster file by default
+    #[arg(long, value_enum, default_value_t)]
+    pub source: SourceKind,
+    /// Attribute path of the node's IP address, relative to the node's configuration. Falls
+    /// back to deployment.targetHost, the first interface address, networking.fqdn and the
+    /// HostName ~/.ssh/config has for the node's name. Colmena, deploy-rs and inventory nodes
+    /// are reached at the host they deploy to instead
+    #[arg(long, default_value = DEFAULT_IP_ATTR)]
+    pub ip_attr: String,
+    /// Flake output attribute containing the nodes, or the directory of the nodes for a NixOS
+    /// directory [default: nixosConfigurations, colmenaHive.nodes or deploy.nodes]
+    #[arg(long, default_value = "")]
+    pub attr_root: String,
+    /// Evaluate the nodes impurely
+    #[arg(long)]
+    pub impure: bool,
+    /// Accept the nix configuration of the cluster's flake
+    #[arg(long)]
+    pub accept_flake_config: bool,
+    /// Override a flake input of the cluster. Can be given multiple times
+    #[arg(long = "override-input", num_args = 2, value_names = ["INPUT", "FLAKE_REF"])]
+    pub override_inputs: Vec<String>,
+    /// Set a nix setting for every nix invocation. Can be given multiple times
+    #[arg(long = "option", num_args = 2, value_names = ["NAME", "VALUE"])]
+    pub options: Vec<String>,
+    /// Extra argument passed to every nix invocation. Can be given multiple times
+    #[arg(long = "nix-arg", allow_hyphen_values = true)]
+    pub nix_args: Vec<String>,
+    /// SSH user, overriding ~/.ssh/config
+    #[arg(long)]
+    pub ssh_user: Option<String>,
+    /// SSH port, overriding ~/.ssh/config
+    #[arg(long)]
+    pub ssh_port: Option<u16>,
+    /// Evaluate the nodes with nix-eval-jobs when checking more than one
+    #[arg(long)]
+    pub nix_eval_jobs: bool,
+    /// Number of nix-eval-jobs workers
+    #[arg(long, requires = "nix_eval_jobs", default_value_t = EvalJobs::default().workers)]
+    pub eval_workers: usize,
+    /// Memory in MiB a nix-eval-jobs worker may use before it's restarted
+    #[arg(long, requires = "nix_eval_jobs", default_value_t = EvalJobs::default().max_memory_mib)]
+    pub eval_max_memory: usize,
+}
+
+impl ClusterArgs {
+    pub fn into_config(self) -> anyhow::Result<ClusterConfig> {
+        Ok(ClusterConfig {
+            cluster_path: resolve_cluster_path(&self.flake)?,
+            source: self.source,
+            flake_ref: self.flake_ref,
+            ip_attr: self.ip_attr,
+            attr_root: self.attr_root,
+            nix_flags: NixFlags {
+                impure: self.impure,
+                accept_flake_config: self.accept_flake_config,
+                override_inputs: pairs(self.override_inputs),
+                options: pairs(self.options),
+            },
+            nix_args: self.nix_args,
+            ssh: SshOverrides {
+                user: self.ssh_user,
+                port: self.ssh_port,
+            },
+            eval_jobs: self.nix_eval_jobs.then_some(EvalJobs {
+                workers: self.eval_workers,
+                max_memory_mib: self.eval_max_memory,
+            }),
+        })
+    }
+}
+
+/// Pairs up the values of an option taking two, which clap collects flat.
+fn pairs(values: Vec<String>) -> BTreeMap<String, String> {
+    let mut values = values.into_iter();
+    let mut pairs = BTreeMap::new();
+    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+        pairs.insert(key, value);
+    }
+    pairs
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    #[command(flatten)]
+    pub cluster: ClusterArgs,
+    /// Name of the node in the nodes attribute
+    #[arg(long)]
+    pub node: String,
+}
+
+pub fn run(command: Command, verbose: bool) -> ExitCode {
+    let result = match command {
+        Command::Diff(args) => run_node_diff(args, verbose),
+        Command::CheckDrift(args) => drift::check_drift(args, verbose),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            if let Some(err) = err.downcast_ref::<DiffError>() {
+                print_error_details(err);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Resolves a user supplied cluster path to the absolute path of its flake.nix or hive.nix,
+/// or of its directory if it has neither.
+pub fn resolve_cluster_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Couldn't find cluster at {}", path.display()))?;
+
+    let config = ClusterConfig {
+        cluster_path: path,
+        ..ClusterConfig::default()
+    };
+    Ok(config.cluster_file())
+}
+
+fn run_node_diff(args: DiffArgs, verbose: bool) -> anyhow::Result<ExitCode> {
+    let config = args.cluster.into_config()?;
+    let diff = diff_node(config, args.node.clone(), verbose, None)?;
+
+    eprintln!(
+        "[{}] {} -> {}{}",
+        args.node,
+        diff.system_path.display(),
+        diff.toplevel.display(),
+        cached_note(&diff)
+    );
+    if diff.up_to_date {
+        eprintln!("[{}] Up to date", args.node);
+    } else {
+        println!("{}", diff.diff);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Tells when a diff taken from the cache was computed, nothing for a fresh one.
+pub fn cached_note(diff: &NodeDiff) -> String {
+    if diff.cached {
+        format!(" (cached, computed {})", format::ago(diff.computed_at))
+    } else {
+        String::new()
+    }
+}
+
+/// Drives `run_diff` for a single node to completion, printing its stages to stderr.
+///
+/// With `verbose`, the output of the commands run for the diff is printed as well.
+pub fn diff_node(
+    config: ClusterConfig,
+    node: String,
+    verbose: bool,
+    prepared: Option<PreparedNode>,
+) -> anyhow::Result<NodeDiff> {
+    // Nodes are diffed one after another, so the job never has to wait for a permit.
+    let job = Scheduler::default().job();
+    let mut diff = pin!(run_diff(config, node.clone(), job, prepared));
+    let mut progress = DiffProgress::default();
+
+    executor::block_on(async {
+        while let Some(event) = diff.next().await {
+            match event? {
+                DiffEvent::Stage(stage) => {
+                    progress.stage_started(stage);
+                    print_progress(&node, &progress);
+                }
+                DiffEvent::Target(target) if verbose => eprintln!(
+                    "[{node}] reached at {} (from {})",
+                    target.host,
+                    target.source.label()
+                ),
+                DiffEvent::Target(_) => {}
+                DiffEvent::Log(line) if verbose => eprintln!("[{node}] {line}"),
+                DiffEvent::Build(event) => {
+                    if let Some(line) = event.text_line().filter(|_| verbose) {
+                        eprintln!("[{node}] {line}");
+                    }
+                    progress.nix_event(&event);
+                }
+                DiffEvent::Log(_) => {}
+                DiffEvent::Finished(diff) => return Ok(diff),
+            }
+        }
+
+        anyhow::bail!("Diff of {node} ended without a result")
+    })
+    .with_context(|| format!("Diff of {node} failed"))
+}
+
+pub fn print_error_details(err: &DiffError) {
+    if let Some(stderr) = err.stderr().filter(|stderr| !stderr.trim().is_empty()) {
+        eprintln!("{}", stderr.trim_end());
+    }
+    eprintln!("hint: {}", err.hint());
+}
+
+pub fn print_progress(node: &str, progress: &DiffProgress) {
+    eprintln!(
+        "[{node}] {:>3.0}% {} ({} elapsed)",
+        progress.fraction() * 100.,
+        progress.stage().label(),
+        format::duration(progress.elapsed())
+    );
+}
@@ -1,22 +1,34 @@
-use iced::{Element, Task, Theme};
+use clap::Parser;
+use iced::widget::{button, column, container, row, text};
+use iced::{Element, Event, Length, Point, Size, Task, Theme, event, window};
+use log::error;
 use std::net::IpAddr;
+use std::process::ExitCode;
 use iced_futures::Subscription;
-use crate::pages::nix_cluster::NixClusterView;
+use crate::cli::Cli;
+use crate::pages::nix_workspace::NixWorkspace;
 use crate::pages::ping::PingPage;
+use crate::pages::registry::{PageMessage, PageRegistry};
+use crate::settings::Settings;
 
+mod cli;
+mod nix;
 mod pages;
+mod settings;
 pub mod utils;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MainMessage {
-    PingView(pages::ping::Message),
-    NixClusterView(pages::nix_cluster::Message),
+    Navigate(usize),
+    Page(usize, PageMessage),
+    WindowResized(Size),
+    WindowMoved(Point),
+    CloseRequested(window::Id),
 }
 
-#[derive(Default)]
 pub struct CheckITApp {
-    ping_page: PingPage,
-    nix_cluster: NixClusterView,
+    pages: PageRegistry,
+    settings: Settings,
 }
 
 pub struct PingProc {
@@ -24,33 +36,119 @@ pub struct PingProc {
 }
 
 impl CheckITApp {
+    fn new(settings: Settings) -> (Self, Task<MainMessage>) {
+        let mut pages = PageRegistry::default()
+            .register(PingPage::default())
+            .register(NixWorkspace::default());
+        pages.set_active(1);
+
+        let restore = pages
+            .restore(&settings)
+            .map(|(idx, msg)| MainMessage::Page(idx, msg));
+
+        (Self { pages, settings }, restore)
+    }
+
     fn update(&mut self, msg: MainMessage) -> Task<MainMessage> {
         match msg {
-            MainMessage::PingView(msg) => {
-                self.ping_page.update(msg);
+            MainMessage::Navigate(idx) => {
+                self.pages.set_active(idx);
+                Task::none()
+            }
+            MainMessage::Page(idx, msg) => self
+                .pages
+                .update(idx, msg)
+                .map(move |msg| MainMessage::Page(idx, msg)),
+            MainMessage::WindowResized(size) => {
+                self.settings.window.width = size.width;
+                self.settings.window.height = size.height;
                 Task::none()
-            },
-            MainMessage::NixClusterView(msg) => self.nix_cluster.update(msg).map(MainMessage::NixClusterView),
+            }
+            MainMessage::WindowMoved(position) => {
+                self.settings.window.x = Some(position.x);
+                self.settings.window.y = Some(position.y);
+                Task::none()
+            }
+            MainMessage::CloseRequested(id) => {
+                self.pages.persist(&mut self.settings);
+                if let Err(err) = self.settings.save() {
+                    error!("Couldn't save settings: {err:?}");
+                }
+                window::close(id)
+            }
         }
     }
-    
-    fn view(&self) -> Element<MainMessage> {
-        // self.ping_page.view().map(|m| MainMessage::PingPage(m))
-        self.nix_cluster.view().map(|m| MainMessage::NixClusterView(m))
+
+    fn view(&self) -> Element<'_, MainMessage> {
+        let sidebar = self
+            .pages
+            .entries()
+            .fold(column![].spacing(5).padding(5), |sidebar, (idx, icon, title)| {
+                let mut page_btn = button(text!("{icon} {title}")).width(Length::Fill);
+                if idx != self.pages.active() {
+                    page_btn = page_btn.on_press(MainMessage::Navigate(idx));
+                }
+                sidebar.push(page_btn)
+            })
+            .width(Length::Fixed(130.));
+
+        let active = self.pages.active();
+        let content = self
+            .pages
+            .view()
+            .map(|page| page.map(move |msg| MainMessage::Page(active, msg)));
+
+        row![container(sidebar).style(container::dark).height(Length::Fill)]
+            .push_maybe(content)
+            .into()
     }
-    
+
     fn subscription(&self) -> Subscription<MainMessage> {
-        // self.ping_page.subscription()
-        //     .map(|m| MainMessage::PingPage(m))
-        Subscription::none()
+        // Pages keep running in the background while hidden, so every page is subscribed.
+        let pages = self
+            .pages
+            .subscription()
+            .map(|(idx, msg)| MainMessage::Page(idx, msg));
+
+        let window_events = event::listen_with(|event, _status, id| match event {
+            Event::Window(window::Event::Resized(size)) => Some(MainMessage::WindowResized(size)),
+            Event::Window(window::Event::Moved(position)) => Some(MainMessage::WindowMoved(position)),
+            Event::Window(window::Event::CloseRequested) => Some(MainMessage::CloseRequested(id)),
+            _ => None,
+        });
+
+        Subscription::batch([pages, window_events])
     }
 }
 
-fn main() -> iced::Result {
+fn main() -> ExitCode {
     env_logger::init();
 
-    iced::application("CheckIT", CheckITApp::update, CheckITApp::view)
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        return cli::run(command, cli.verbose);
+    }
+
+    let settings = Settings::load();
+    let geometry = &settings.window;
+    let position = match (geometry.x, geometry.y) {
+        (Some(x), Some(y)) => window::Position::Specific(Point::new(x, y)),
+        _ => window::Position::default(),
+    };
+
+    let result = iced::application("CheckIT", CheckITApp::update, CheckITApp::view)
         .theme(|_| Theme::CatppuccinMocha)
         .subscription(CheckITApp::subscription)
-        .run()
+        .window_size(Size::new(geometry.width, geometry.height))
+        .position(position)
+        .exit_on_close_request(false)
+        .run_with(move || CheckITApp::new(settings));
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("CheckIT exited with an error: {err}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,138 @@
+//! Evaluating and building many nodes at once, instead of once per node diff.
+//!
+//! A single evaluation by the cluster's source, or nix-eval-jobs when configured, evaluates
+//! where to reach every node and its system derivation, then a single `nix build` builds all
+//! systems. The results let [`run_diff`](crate::nix::diff::run_diff) skip straight to reaching
+//! the node.
+
+use crate::nix::build_log::BuildEvent;
+use crate::nix::diff::DiffStage;
+use crate::nix::error::DiffError;
+use crate::nix::process::CommandLog;
+use crate::nix::scheduler::{Job, JobKind};
+use crate::nix::source::{self, EvaluatedNode, Target};
+use crate::nix::{ClusterConfig, build_derivations, eval_jobs};
+use async_stream::stream;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt, future, stream};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+/// A node to prepare, with the attribute path of its IP address for sources that need one.
+#[derive(Debug, Clone)]
+pub struct BatchNode {
+    pub name: String,
+    pub ip_attr: String,
+}
+
+/// What the batch found out about a node ahead of its diff.
+#[derive(Debug, Clone)]
+pub struct PreparedNode {
+    pub target: Target,
+    /// The built system toplevel from the cluster.
+    pub toplevel: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchEvent {
+    /// Either [`DiffStage::EvaluatingIp`] or [`DiffStage::BuildingToplevel`], for all nodes.
+    Stage(DiffStage),
+    Log(String),
+    Build(BuildEvent),
+    /// A node finished evaluating.
+    Evaluated(NodeEvaluation),
+    Finished(BTreeMap<String, PreparedNode>),
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeEvaluation {
+    pub node: String,
+    /// Where to reach the node and its system derivation, or why it couldn't be evaluated.
+    pub result: Result<EvaluatedNode, String>,
+}
+
+/// Evaluates and builds `nodes` with one nix invocation each.
+///
+/// Any failure fails the whole batch, except for single nodes failing to evaluate with
+/// nix-eval-jobs. Diffing the nodes one by one then tells which node is at fault.
+pub fn prepare_nodes(
+    config: ClusterConfig,
+    nodes: Vec<BatchNode>,
+    job: Job,
+) -> impl Stream<Item = Result<BatchEvent, DiffError>> {
+    let (log, log_lines) = CommandLog::channel();
+    let log_lines = log_lines.map(|line| match BuildEvent::parse(&line) {
+        Some(event) => Ok(BatchEvent::Build(event)),
+        None => Ok(BatchEvent::Log(line)),
+    });
+    // nix-eval-jobs prints each node as soon as it's evaluated.
+    let (eval_results, eval_lines) = mpsc::unbounded();
+    let eval_lines = eval_lines.filter_map(|line: String| {
+        future::ready(eval_jobs::parse_line(&line).map(|node| Ok(BatchEvent::Evaluated(node))))
+    });
+
+    let pipeline = stream! {
+        let stage = DiffStage::EvaluatingIp;
+        yield Ok(BatchEvent::Stage(stage));
+
+        let permit = job.acquire(JobKind::Eval).await;
+        let eval_jobs = match config.eval_jobs {
+            Some(settings) => {
+                let nodes_expression =
+                    source::nodes_expression(&config).await.map_err(stage.fail())?;
+                if nodes_expression.is_none() {
+                    log.line("nix-eval-jobs can't evaluate this cluster, evaluating it as usual");
+                }
+                nodes_expression.map(|expr| (settings, expr))
+            }
+            None => None,
+        };
+        let evaluated: BTreeMap<_, _> = match eval_jobs {
+            Some((settings, expr)) => {
+                eval_jobs::evaluate(&config, settings, &expr, &nodes, &log, eval_results)
+                    .await
+                    .map_err(stage.fail())?
+                    .into_iter()
+                    .filter_map(|node| Some((node.node, node.result.ok()?)))
+                    .collect()
+            }
+            None => {
+                let evaluated = source::evaluate_nodes(&config, &nodes, &log)
+                    .await
+                    .map_err(stage.fail())?;
+                for (node, result) in &evaluated {
+                    yield Ok(BatchEvent::Evaluated(NodeEvaluation {
+                        node: node.clone(),
+                        result: Ok(result.clone()),
+                    }));
+                }
+                evaluated
+            }
+        };
+        drop(permit);
+
+        let stage = DiffStage::BuildingToplevel;
+        yield Ok(BatchEvent::Stage(stage));
+
+        let drv_paths: Vec<_> = evaluated.values().map(|node| node.drv_path.as_str()).collect();
+        let mut outputs = if drv_paths.is_empty() {
+            HashMap::new()
+        } else {
+            let _permit = job.acquire(JobKind::Build).await;
+            let what = format!("{} node systems", drv_paths.len());
+            build_derivations(&config, &drv_paths, &what, &log).await.map_err(stage.fail())?
+        };
+
+        let prepared = evaluated
+            .into_iter()
+            .filter_map(|(name, node)| {
+                let toplevel = outputs.remove(&node.drv_path)?;
+                Some((name, PreparedNode { target: node.target, toplevel }))
+            })
+            .collect();
+        yield Ok(BatchEvent::Finished(prepared));
+    };
+
+    // The log and evaluation results end once the pipeline finished and dropped their senders.
+    stream::select(pipeline, stream::select(log_lines, eval_lines))
+}
@@ -0,0 +1,357 @@
+//! Following a `nix build --log-format internal-json` as it runs.
+//!
+//! Every line nix writes to stderr in this format is `@nix ` followed by a JSON object
+//! describing an activity starting or stopping, a result of an activity or a message.
+
+use crate::utils::ansi_to_rich::strip_ansi;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+
+/// Activity and result type ids, as defined by nix' `ActivityType` and `ResultType`.
+mod kind {
+    pub const COPY_PATH: u64 = 100;
+    pub const COPY_PATHS: u64 = 103;
+    pub const BUILDS: u64 = 104;
+    pub const BUILD: u64 = 105;
+
+    pub const BUILD_LOG_LINE: u64 = 101;
+    pub const SET_PHASE: u64 = 104;
+    pub const PROGRESS: u64 = 105;
+    pub const SET_EXPECTED: u64 = 106;
+    pub const POST_BUILD_LOG_LINE: u64 = 107;
+}
+
+/// nix' `lvlInfo`, the most verbose messages nix shows by default.
+const MAX_MSG_LEVEL: u8 = 3;
+
+/// Arguments making nix write its log in the format understood by [`BuildEvent::parse`].
+pub const LOG_FORMAT_ARGS: [&str; 2] = ["--log-format", "internal-json"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum BuildEvent {
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        kind: u64,
+        #[serde(default)]
+        fields: Vec<Value>,
+    },
+    Msg {
+        level: u8,
+        msg: String,
+    },
+}
+
+impl BuildEvent {
+    /// Parses a line of a nix log in internal-json format.
+    ///
+    /// Returns `None` for lines nix didn't write in that format.
+    pub fn parse(line: &str) -> Option<Self> {
+        let json = line.strip_prefix("@nix ")?;
+        serde_json::from_str(json).ok()
+    }
+
+    /// The human readable text of the event, if it carries any.
+    ///
+    /// Messages more verbose than nix' default verbosity are left out.
+    pub fn text_line(&self) -> Option<String> {
+        match self {
+            BuildEvent::Msg { level, msg } if *level <= MAX_MSG_LEVEL => Some(strip_ansi(msg)),
+            BuildEvent::Result { kind, fields, .. }
+                if matches!(*kind, kind::BUILD_LOG_LINE | kind::POST_BUILD_LOG_LINE) =>
+            {
+                fields.first().and_then(Value::as_str).map(strip_ansi)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Turns the stderr of a command run with [`LOG_FORMAT_ARGS`] back into plain text.
+pub fn plain_text(stderr: &str) -> String {
+    stderr
+        .lines()
+        .filter_map(|line| match BuildEvent::parse(line) {
+            Some(event) => event.text_line(),
+            None => Some(line.to_owned()),
+        })
+        .fold(String::new(), |mut text, line| {
+            text.push_str(&line);
+            text.push('\n');
+            text
+        })
+}
+
+#[derive(Debug, Default)]
+struct Activity {
+    kind: u64,
+    /// Derivation of a build activity.
+    drv: Option<String>,
+    phase: Option<String>,
+    done: u64,
+    expected: u64,
+    /// Work this activity announced for activities of other types.
+    expected_by_kind: HashMap<u64, u64>,
+    log: VecDeque<String>,
+}
+
+/// State of a running `nix build` or `nix copy`, folded from its [`BuildEvent`]s.
+#[derive(Debug, Default)]
+pub struct BuildProgress {
+    activities: HashMap<u64, Activity>,
+    /// Running build activities, most recently started last.
+    running_builds: Vec<u64>,
+}
+
+impl BuildProgress {
+    /// Lines of the active build's log that are kept.
+    pub const LOG_TAIL: usize = 50;
+
+    pub fn apply(&mut self, event: &BuildEvent) {
+        match event {
+            BuildEvent::Start { id, kind, fields } => {
+                let mut drv = None;
+                if *kind == kind::BUILD {
+                    drv = fields.first().and_then(Value::as_str).map(str::to_owned);
+                    self.running_builds.push(*id);
+                }
+                self.activities.insert(
+                    *id,
+                    Activity {
+                        kind: *kind,
+                        drv,
+                        ..Activity::default()
+                    },
+                );
+            }
+            BuildEvent::Stop { id } => {
+                self.running_builds.retain(|build| build != id);
+                if let Some(activity) = self.activities.get_mut(id) {
+                    activity.log.clear();
+                }
+            }
+            BuildEvent::Result { id, kind, fields } => {
+                let Some(activity) = self.activities.get_mut(id) else {
+                    return;
+                };
+                let int = |idx: usize| fields.get(idx).and_then(Value::as_u64).unwrap_or_default();
+
+                match *kind {
+                    kind::BUILD_LOG_LINE | kind::POST_BUILD_LOG_LINE => {
+                        if let Some(line) = event.text_line() {
+                            if activity.log.len() >= Self::LOG_TAIL {
+                                activity.log.pop_front();
+                            }
+                            activity.log.push_back(line);
+                        }
+                    }
+                    kind::SET_PHASE => {
+                        activity.phase = fields.first().and_then(Value::as_str).map(str::to_owned);
+                    }
+                    kind::PROGRESS => {
+                        activity.done = int(0);
+                        activity.expected = int(1);
+                    }
+                    kind::SET_EXPECTED => {
+                        activity.expected_by_kind.insert(int(0), int(1));
+                    }
+                    _ => {}
+                }
+            }
+            BuildEvent::Msg { .. } => {}
+        }
+    }
+
+    /// Done and expected work of all activities of `kind`, like nix' own progress bar counts it.
+    ///
+    /// Parents announce the total of their children's work, which the children report again on
+    /// their own, so the larger of both sums is expected rather than their sum.
+    fn totals(&self, kind: u64) -> (u64, u64) {
+        let (done, expected) = self
+            .activities
+            .values()
+            .filter(|activity| activity.kind == kind)
+            .fold((0, 0), |(done, expected), activity| {
+                (done + activity.done, expected + activity.expected)
+            });
+        let announced: u64 = self
+            .activities
+            .values()
+            .filter_map(|activity| activity.expected_by_kind.get(&kind))
+            .sum();
+        (done, expected.max(announced))
+    }
+
+    /// Built and expected derivations.
+    pub fn builds(&self) -> (u64, u64) {
+        self.totals(kind::BUILDS)
+    }
+
+    /// Downloaded and expected store paths.
+    pub fn downloads(&self) -> (u64, u64) {
+        self.totals(kind::COPY_PATHS)
+    }
+
+    /// Derivations that still have to be built.
+    pub fn builds_remaining(&self) -> u64 {
+        let (done, expected) = self.builds();
+        expected.saturating_sub(done)
+    }
+
+    /// Store paths that still have to be downloaded.
+    pub fn downloads_remaining(&self) -> u64 {
+        let (done, expected) = self.downloads();
+        expected.saturating_sub(done)
+    }
+
+    /// Copied and expected bytes of store paths, as reported by `nix copy`.
+    pub fn bytes_copied(&self) -> (u64, u64) {
+        self.totals(kind::COPY_PATH)
+    }
+
+    fn active_build(&self) -> Option<&Activity> {
+        self.running_builds
+            .last()
+            .and_then(|id| self.activities.get(id))
+    }
+
+    /// Name of the derivation that started building most recently and is still running.
+    pub fn current_drv(&self) -> Option<&str> {
+        self.active_build()?.drv.as_deref().map(drv_name)
+    }
+
+    /// Build phase of the current derivation, e.g. `buildPhase`.
+    pub fn current_phase(&self) -> Option<&str> {
+        self.active_build()?.phase.as_deref()
+    }
+
+    /// The last lines of the current derivation's build log.
+    pub fn log_tail(&self) -> impl Iterator<Item = &str> {
+        self.active_build()
+            .into_iter()
+            .flat_map(|build| build.log.iter().map(String::as_str))
+    }
+}
+
+/// `/nix/store/<hash>-hello-2.12.drv` -> `hello-2.12`
+fn drv_name(drv: &str) -> &str {
+    let name = drv
+        .strip_prefix("/nix/store/")
+        .and_then(|name| name.split_once('-'))
+        .map_or(drv, |(_, name)| name);
+    name.strip_suffix(".drv").unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(lines: &str) -> BuildProgress {
+        let mut progress = BuildProgress::default();
+        for line in lines.lines() {
+            if let Some(event) = BuildEvent::parse(line.trim()) {
+                progress.apply(&event);
+            }
+        }
+        progress
+    }
+
+    const COPY_START: &str = r#"
+        @nix {"action":"start","id":1,"level":3,"parent":0,"text":"copying 2 paths","type":103}
+        @nix {"action":"result","fields":[100,3000],"id":1,"type":106}
+        @nix {"action":"result","fields":[0,2,1,0],"id":1,"type":105}
+        @nix {"action":"start","fields":["/nix/store/aaaa-foo","ssh://root@node","local"],"id":2,"level":3,"parent":1,"text":"copying path '/nix/store/aaaa-foo' from 'ssh://root@node'","type":100}
+        @nix {"action":"result","fields":[500,1000,0,0],"id":2,"type":105}
+    "#;
+
+    const COPY_END: &str = r#"
+        @nix {"action":"result","fields":[1000,1000,0,0],"id":2,"type":105}
+        @nix {"action":"stop","id":2}
+        @nix {"action":"result","fields":[1,2,1,0],"id":1,"type":105}
+        @nix {"action":"start","fields":["/nix/store/bbbb-bar","ssh://root@node","local"],"id":3,"level":3,"parent":1,"text":"copying path '/nix/store/bbbb-bar' from 'ssh://root@node'","type":100}
+        @nix {"action":"result","fields":[2000,2000,0,0],"id":3,"type":105}
+        @nix {"action":"stop","id":3}
+        @nix {"action":"result","fields":[2,2,0,0],"id":1,"type":105}
+        @nix {"action":"stop","id":1}
+    "#;
+
+    #[test]
+    fn copied_bytes_count_announced_total_once() {
+        let running = progress(COPY_START);
+        assert_eq!(running.bytes_copied(), (500, 3000));
+        assert_eq!(running.downloads(), (0, 2));
+
+        let finished = progress(&format!("{COPY_START}{COPY_END}"));
+        assert_eq!(finished.bytes_copied(), (3000, 3000));
+        assert_eq!(finished.downloads_remaining(), 0);
+    }
+
+    #[test]
+    fn copied_bytes_without_announced_total() {
+        let copy = progress(
+            r#"
+            @nix {"action":"start","fields":["/nix/store/aaaa-foo","ssh://root@node","local"],"id":2,"level":3,"parent":0,"text":"","type":100}
+            @nix {"action":"result","fields":[250,1000,0,0],"id":2,"type":105}
+            "#,
+        );
+        assert_eq!(copy.bytes_copied(), (250, 1000));
+    }
+
+    #[test]
+    fn follows_running_build() {
+        let build = progress(
+            r#"
+            @nix {"action":"start","id":1,"level":5,"parent":0,"text":"","type":104}
+            @nix {"action":"result","fields":[0,2,0,0],"id":1,"type":105}
+            @nix {"action":"start","fields":["/nix/store/cccc-hello-2.12.drv","",1,1],"id":2,"level":3,"parent":0,"text":"building '/nix/store/cccc-hello-2.12.drv'","type":105}
+            @nix {"action":"result","fields":["buildPhase"],"id":2,"type":104}
+            @nix {"action":"result","fields":["\u001b[1mgcc\u001b[0m -o hello"],"id":2,"type":101}
+            @nix {"action":"result","fields":[0,2,1,0],"id":1,"type":105}
+            this line isn't json
+            "#,
+        );
+        assert_eq!(build.current_drv(), Some("hello-2.12"));
+        assert_eq!(build.current_phase(), Some("buildPhase"));
+        assert_eq!(build.log_tail().collect::<Vec<_>>(), ["gcc -o hello"]);
+        assert_eq!(build.builds_remaining(), 2);
+    }
+
+    #[test]
+    fn finished_build_is_no_longer_current() {
+        let build = progress(
+            r#"
+            @nix {"action":"start","fields":["/nix/store/cccc-hello-2.12.drv","",1,1],"id":2,"level":3,"parent":0,"text":"","type":105}
+            @nix {"action":"result","fields":["gcc -o hello"],"id":2,"type":101}
+            @nix {"action":"stop","id":2}
+            "#,
+        );
+        assert_eq!(build.current_drv(), None);
+        assert_eq!(build.log_tail().count(), 0);
+    }
+
+    #[test]
+    fn plain_text_keeps_messages_and_log_lines() {
+        let stderr = concat!(
+            "@nix {\"action\":\"msg\",\"level\":0,\"msg\":\"error: build failed\"}\n",
+            "@nix {\"action\":\"msg\",\"level\":6,\"msg\":\"debug noise\"}\n",
+            "@nix {\"action\":\"result\",\"fields\":[\"make: ***\"],\"id\":2,\"type\":101}\n",
+            "warning: not json\n",
+        );
+        assert_eq!(
+            plain_text(stderr),
+            "error: build failed\nmake: ***\nwarning: not json\n"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Finished diffs on disk, keyed by the two systems they compare.
+//!
+//! Store paths never change their contents, so a diff between the same pair of systems is
+//! valid forever and diffing them again is instant. Entries are JSON files in
+//! `$XDG_CACHE_HOME/checkit/diffs`.
+
+use crate::nix::diff::NodeDiff;
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The pair of systems a diff compares.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffKey {
+    /// The system the node was running.
+    pub system_path: PathBuf,
+    /// The system built from the cluster.
+    pub toplevel: PathBuf,
+}
+
+impl DiffKey {
+    /// File of the cached diff, named after both store paths. Paths below a store path, like
+    /// files inside a toplevel, aren't cached as their names don't tell the systems apart.
+    fn entry_path(&self) -> Option<PathBuf> {
+        let name = |path: &Path| {
+            let relative = path.strip_prefix("/nix/store").ok()?;
+            let mut components = relative.components();
+            let name = components.next()?;
+            components
+                .next()
+                .is_none()
+                .then(|| name.as_os_str().to_string_lossy().into_owned())
+        };
+        let file = format!(
+            "{}_{}.json",
+            name(&self.system_path)?,
+            name(&self.toplevel)?
+        );
+        dirs::cache_dir().map(|dir| dir.join("checkit").join("diffs").join(file))
+    }
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+/// The cached diff of `key`, marked as [`cached`](NodeDiff::cached).
+///
+/// Blocks on the file system.
+pub fn load(key: &DiffKey) -> Option<NodeDiff> {
+    let path = key.entry_path()?;
+    if !path.exists() {
+        return None;
+    }
+
+    let diff = fs::read_to_string(&path)
+        .with_context(|| format!("Couldn't read {}", path.display()))
+        .and_then(|raw| serde_json::from_str::<NodeDiff>(&raw).context("Couldn't parse diff"));
+    match diff {
+        Ok(diff) if diff.key() != *key => {
+            warn!(
+                "Ignoring cached diff of other systems in {}",
+                path.display()
+            );
+            None
+        }
+        Ok(diff) => Some(NodeDiff {
+            cached: true,
+            ..diff
+        }),
+        Err(err) => {
+            warn!("Ignoring cached diff: {err:?}");
+            None
+        }
+    }
+}
+
+/// Caches `diff` under the systems it compares. Failing to is only logged, the diff is still
+/// good without the cache.
+///
+/// Blocks on the file system.
+pub fn store(diff: &NodeDiff) {
+    let result = diff
+        .key()
+        .entry_path()
+        .context("No cache directory available")
+        .and_then(|path| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Couldn't create {}", dir.display()))?;
+            }
+            let raw = serde_json::to_string(diff).context("Couldn't serialize diff")?;
+            fs::write(&path, raw).with_context(|| format!("Couldn't write {}", path.display()))
+        });
+
+    if let Err(err) = result {
+        warn!("Failed to cache diff: {err:?}");
+    }
+}
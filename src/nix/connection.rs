@@ -0,0 +1,141 @@
+//! Connections to the SSH server of a node, directly or through a jump host.
+
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use std::io::{BufRead, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// How long to wait for a node to accept the SSH connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a single SSH call may block, so a cancelled diff can't leave its thread waiting on
+/// a node that stopped responding.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A connection to a node, to hand to an SSH session.
+///
+/// Through a jump host the connection is a socket to an `ssh -W` process forwarding it,
+/// which is stopped once the connection is dropped.
+pub struct Connection {
+    socket: OwnedFd,
+    proxy: Option<Child>,
+}
+
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(proxy) = &mut self.proxy {
+            let _ = proxy.kill();
+            let _ = proxy.wait();
+        }
+    }
+}
+
+/// Connects to port `port` of `host`, through `jump` if given. The stderr of the jump is
+/// written to `log`.
+///
+/// Blocks on the network.
+pub fn connect(
+    host: &str,
+    port: u16,
+    jump: Option<&str>,
+    log: &CommandLog,
+) -> Result<Connection, NixError> {
+    match jump {
+        Some(jump) => connect_through(host, port, jump, log),
+        None => connect_directly(host, port).map(|stream| Connection {
+            socket: stream.into(),
+            proxy: None,
+        }),
+    }
+}
+
+/// Opens a TCP connection to the first address of `host` that accepts one.
+fn connect_directly(host: &str, port: u16) -> Result<TcpStream, NixError> {
+    let connect_error = |message: String| NixError::TcpConnect {
+        addr: format!("{host}:{port}"),
+        message,
+    };
+
+    let mut last_error = None;
+    for addr in (host, port)
+        .to_socket_addrs()
+        .map_err(|err| connect_error(err.to_string()))?
+    {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(connection) => return Ok(connection),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(connect_error(last_error.map_or_else(
+        || "Host name didn't resolve to any address".to_owned(),
+        |err| err.to_string(),
+    )))
+}
+
+/// Has ssh log into `jump`, given as `[user@]host[:port]`, and forward a connection to
+/// `host` from there.
+fn connect_through(
+    host: &str,
+    port: u16,
+    jump: &str,
+    log: &CommandLog,
+) -> Result<Connection, NixError> {
+    let connect_error = |message: String| NixError::TcpConnect {
+        addr: format!("{host}:{port} through {jump}"),
+        message,
+    };
+
+    let forward = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let (socket, proxy_end) = UnixStream::pair().map_err(|err| connect_error(err.to_string()))?;
+    let proxy_output = proxy_end
+        .try_clone()
+        .map_err(|err| connect_error(err.to_string()))?;
+    let connect_timeout = format!("ConnectTimeout={}", CONNECT_TIMEOUT.as_secs());
+    let destination = format!("ssh://{jump}");
+    let args = [
+        "-o",
+        "BatchMode=yes",
+        "-o",
+        &connect_timeout,
+        "-W",
+        &forward,
+        &destination,
+    ];
+    log.line(format!("$ ssh {}", args.join(" ")));
+    let mut proxy = Command::new("ssh")
+        .args(args)
+        .stdin(OwnedFd::from(proxy_end))
+        .stdout(OwnedFd::from(proxy_output))
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| NixError::spawn("ssh", err))?;
+
+    if let Some(stderr) = proxy.stderr.take() {
+        let log = log.clone();
+        blocking::unblock(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                log.line(line);
+            }
+        })
+        .detach();
+    }
+
+    Ok(Connection {
+        socket: socket.into(),
+        proxy: Some(proxy),
+    })
+}
@@ -0,0 +1,628 @@
+use crate::nix::batch::PreparedNode;
+use crate::nix::build_log::{self, BuildEvent};
+use crate::nix::cache::{self, DiffKey};
+use crate::nix::connection;
+use crate::nix::error::{DiffError, NixError};
+use crate::nix::history::{self, HistoryEntry};
+use crate::nix::process::{CommandLog, unblock};
+use crate::nix::scheduler::{Job, JobKind};
+use crate::nix::source::{self, AddressSource, Target, flake};
+use crate::nix::{ClusterConfig, build_derivations};
+use async_stream::stream;
+use duct::cmd;
+use futures::{Stream, StreamExt, stream};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ssh2_config::{ParseRule, SshConfig};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The steps `run_diff` goes through, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiffStage {
+    #[default]
+    EvaluatingIp,
+    LocatingCluster,
+    BuildingToplevel,
+    ReadingSshConfig,
+    Connecting,
+    Handshake,
+    Authenticating,
+    OpeningSftp,
+    ResolvingSystem,
+    CopyingClosure,
+    Diffing,
+}
+
+impl DiffStage {
+    pub const ALL: [DiffStage; 11] = [
+        DiffStage::EvaluatingIp,
+        DiffStage::LocatingCluster,
+        DiffStage::BuildingToplevel,
+        DiffStage::ReadingSshConfig,
+        DiffStage::Connecting,
+        DiffStage::Handshake,
+        DiffStage::Authenticating,
+        DiffStage::OpeningSftp,
+        DiffStage::ResolvingSystem,
+        DiffStage::CopyingClosure,
+        DiffStage::Diffing,
+    ];
+
+    /// Sum of the weights of all stages.
+    pub const TOTAL_WEIGHT: u32 = {
+        let mut total = 0;
+        let mut idx = 0;
+        while idx < Self::ALL.len() {
+            total += Self::ALL[idx].weight();
+            idx += 1;
+        }
+        total
+    };
+
+    /// Rough share of a whole diff's duration spent in this stage, out of [`Self::TOTAL_WEIGHT`].
+    pub const fn weight(self) -> u32 {
+        match self {
+            DiffStage::EvaluatingIp => 8,
+            DiffStage::BuildingToplevel => 50,
+            DiffStage::Connecting => 2,
+            DiffStage::CopyingClosure => 30,
+            DiffStage::Diffing => 4,
+            DiffStage::LocatingCluster
+            | DiffStage::ReadingSshConfig
+            | DiffStage::Handshake
+            | DiffStage::Authenticating
+            | DiffStage::OpeningSftp
+            | DiffStage::ResolvingSystem => 1,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DiffStage::EvaluatingIp => "Evaluating node address and system",
+            DiffStage::LocatingCluster => "Locating cluster directory",
+            DiffStage::BuildingToplevel => "Building local system toplevel",
+            DiffStage::ReadingSshConfig => "Reading SSH config",
+            DiffStage::Connecting => "Connecting to node",
+            DiffStage::Handshake => "Performing SSH handshake",
+            DiffStage::Authenticating => "Authenticating via SSH agent",
+            DiffStage::OpeningSftp => "Opening SFTP session",
+            DiffStage::ResolvingSystem => "Resolving remote system path",
+            DiffStage::CopyingClosure => "Copying remote system closure",
+            DiffStage::Diffing => "Diffing system closures",
+        }
+    }
+
+    /// Attributes an error to this stage.
+    pub fn fail(self) -> impl FnOnce(NixError) -> DiffError {
+        move |error| DiffError::new(self, error)
+    }
+}
+
+/// Outcome of a finished node diff.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeDiff {
+    /// The system the node is currently running.
+    pub system_path: PathBuf,
+    /// The freshly built system toplevel from the cluster.
+    pub toplevel: PathBuf,
+    /// nvd output, colored with ANSI escapes. Empty if the node is up to date.
+    pub diff: String,
+    /// The node already runs the configured system, so its closure wasn't copied or diffed.
+    pub up_to_date: bool,
+    /// Seconds since the unix epoch at which the diff was computed.
+    pub computed_at: u64,
+    /// The diff was read from the cache instead of being computed just now.
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+impl NodeDiff {
+    fn new(system_path: PathBuf, toplevel: PathBuf, diff: String, up_to_date: bool) -> Self {
+        Self {
+            system_path,
+            toplevel,
+            diff,
+            up_to_date,
+            computed_at: cache::now(),
+            cached: false,
+        }
+    }
+
+    pub fn key(&self) -> DiffKey {
+        DiffKey {
+            system_path: self.system_path.clone(),
+            toplevel: self.toplevel.clone(),
+        }
+    }
+}
+
+/// Whether a node runs its configured system, as found by its last diff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeStatus {
+    UpToDate,
+    Drifted,
+    Error,
+}
+
+impl NodeStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            NodeStatus::UpToDate => "up to date",
+            NodeStatus::Drifted => "drifted",
+            NodeStatus::Error => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DiffEvent {
+    Stage(DiffStage),
+    /// Where the node is reached, once it's known and again if ~/.ssh/config resolved it.
+    Target(Target),
+    /// A line written to stderr by one of the commands run for the diff.
+    Log(String),
+    /// Progress of the `nix build` of the node's system or the `nix copy` of its closure.
+    Build(BuildEvent),
+    Finished(NodeDiff),
+}
+
+/// Builds the system derivation `drv_path` of `node_name`, returning its store path.
+async fn build_toplevel(
+    config: &ClusterConfig,
+    node_name: &str,
+    drv_path: &str,
+    log: &CommandLog,
+) -> Result<PathBuf, NixError> {
+    let what = format!("system of {node_name}");
+    let mut outputs = build_derivations(config, &[drv_path], &what, log).await?;
+    outputs
+        .remove(drv_path)
+        .ok_or_else(|| NixError::InvalidOutput {
+            expected: format!("the output of {drv_path}"),
+            output: String::new(),
+        })
+}
+
+/// Whether two systems are the same, by their store path or else by the derivation they
+/// were built from. Derivers nix doesn't know never match.
+fn same_system(system: &Path, toplevel: &Path, derivers: Option<(&str, &str)>) -> bool {
+    system == toplevel
+        || derivers.is_some_and(|(system, toplevel)| system == toplevel && system.ends_with(".drv"))
+}
+
+/// The store path `path` lies in, `/nix/store/<hash>-<name>` without anything below it.
+fn store_path_root(path: &Path) -> PathBuf {
+    let store = Path::new("/nix/store");
+    path.strip_prefix(store)
+        .ok()
+        .and_then(|relative| relative.components().next())
+        .map_or_else(|| path.to_owned(), |name| store.join(name))
+}
+
+/// The derivation `path` was built from according to the local store, if it's known.
+async fn local_deriver(config: &ClusterConfig, path: &Path, log: &CommandLog) -> Option<String> {
+    let path = path.to_string_lossy();
+    let args = config.nix_args(&["path-info", "--json", &path]);
+    let output = log
+        .run(format!("nix {}", args.join(" ")), cmd("nix", &args))
+        .await
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    // Older nix prints a list of path infos, newer nix an object keyed by path.
+    let infos: Value = serde_json::from_str(&output.stdout).ok()?;
+    let info = match &infos {
+        Value::Array(infos) => infos.first()?,
+        Value::Object(infos) => infos.values().next()?,
+        _ => return None,
+    };
+    info.get("deriver")?.as_str().map(ToOwned::to_owned)
+}
+
+/// The derivation `path` was built from according to the node's store, if it's known.
+///
+/// Blocks on the network.
+fn remote_deriver(session: &ssh2::Session, path: &Path) -> Option<String> {
+    let mut channel = session.channel_session().ok()?;
+    channel
+        .exec(&format!("nix-store --query --deriver '{}'", path.display()))
+        .ok()?;
+    let mut deriver = String::new();
+    channel.read_to_string(&mut deriver).ok()?;
+    channel.wait_close().ok()?;
+    (channel.exit_status().ok()? == 0).then(|| deriver.trim().to_owned())
+}
+
+/// Records `diff` in the node's history and, unless it came from there, caches it for the
+/// next diff of the same systems. Hands the diff back afterwards.
+async fn finish(
+    config: &ClusterConfig,
+    node_name: &str,
+    flake_rev: Option<String>,
+    diff: NodeDiff,
+) -> NodeDiff {
+    let cluster_file = config.cluster_file();
+    let node_name = node_name.to_owned();
+    unblock(move || {
+        if !diff.cached {
+            cache::store(&diff);
+        }
+        let entry = HistoryEntry {
+            checked_at: cache::now(),
+            flake_rev,
+            diff,
+        };
+        history::record(&cluster_file, &node_name, &entry);
+        entry.diff
+    })
+    .await
+}
+
+/// Diffs the system running on `node_name` against its configuration in the cluster.
+///
+/// Besides the stages and the result, the stream carries the stderr of every command
+/// that is run along the way as [`DiffEvent::Log`], except for the logs of the system's
+/// build and copy which are parsed into [`DiffEvent::Build`].
+///
+/// The evaluation, build and copy only start once `job` got a permit for them, a stage
+/// waiting on one is reported as started already. With `prepared`, the node's target and system
+/// come from [`prepare_nodes`](crate::nix::batch::prepare_nodes) and aren't evaluated or
+/// built again.
+///
+/// Dropping the stream cancels the diff, killing the command it's waiting on and closing
+/// the SSH session to the node.
+pub fn run_diff(
+    config: ClusterConfig,
+    node_name: String,
+    job: Job,
+    prepared: Option<PreparedNode>,
+) -> impl Stream<Item = Result<DiffEvent, DiffError>> {
+    let (log, log_lines) = CommandLog::channel();
+    let log_lines = log_lines.map(|line| match BuildEvent::parse(&line) {
+        Some(event) => Ok(DiffEvent::Build(event)),
+        None => Ok(DiffEvent::Log(line)),
+    });
+
+    // The log ends once the pipeline finished and dropped its end of the log.
+    let pipeline = diff_pipeline(config, node_name, job, prepared, log);
+    stream::select(pipeline, log_lines)
+}
+
+fn diff_pipeline(
+    config: ClusterConfig,
+    node_name: String,
+    job: Job,
+    prepared: Option<PreparedNode>,
+    log: CommandLog,
+) -> impl Stream<Item = Result<DiffEvent, DiffError>> {
+    stream! {
+        let stage = DiffStage::EvaluatingIp;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let (target, drv_path) = match prepared {
+            Some(prepared) => (prepared.target, Err(prepared.toplevel)),
+            None => {
+                let _permit = job.acquire(JobKind::Eval).await;
+                let node = source::evaluate_node(&config, &node_name, &config.ip_attr, &log)
+                    .await
+                    .map_err(stage.fail())?;
+                (node.target, Ok(node.drv_path))
+            }
+        };
+        yield Ok(DiffEvent::Target(target.clone()));
+
+        let stage = DiffStage::LocatingCluster;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let cluster_dir = config.cluster_dir().map_err(stage.fail())?;
+        // The checkout only says what was diffed if the cluster is evaluated from it.
+        let flake_rev = match config.flake_ref.trim() {
+            "" => history::flake_rev(&cluster_dir, &log).await,
+            flake_ref => flake::pinned_rev(flake_ref).map(ToOwned::to_owned),
+        };
+
+        let stage = DiffStage::BuildingToplevel;
+        yield Ok(DiffEvent::Stage(stage));
+
+        // A prepared node's system is built already.
+        let new_drv = match drv_path {
+            Err(toplevel) => toplevel,
+            Ok(drv_path) => {
+                let _permit = job.acquire(JobKind::Build).await;
+                build_toplevel(&config, &node_name, &drv_path, &log)
+                    .await
+                    .map_err(stage.fail())?
+            }
+        };
+
+        let stage = DiffStage::ReadingSshConfig;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let ssh_config = unblock(|| SshConfig::parse_default_file(ParseRule::STRICT))
+            .await
+            .map_err(|err| NixError::SshConfig { message: err.to_string() })
+            .map_err(stage.fail())?;
+
+        let stage = DiffStage::Connecting;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let params = ssh_config.query(&target.host);
+        if target.source == AddressSource::SshConfig {
+            // The node's name only stands in for its address if ssh knows where it is.
+            let Some(host_name) = &params.host_name else {
+                yield Err(DiffError::new(stage, NixError::NoAddress { node: node_name.clone() }));
+                return;
+            };
+            yield Ok(DiffEvent::Target(Target {
+                host: host_name.clone(),
+                ..target.clone()
+            }));
+        }
+        let Target { host, port, user, jump, .. } = target;
+        let addr = params.host_name.unwrap_or_else(|| host.clone());
+        let port = config.ssh.port.or(port).or(params.port).unwrap_or(22);
+        let username = config
+            .ssh
+            .user
+            .clone()
+            .or(user)
+            .or(params.user)
+            .unwrap_or_else(whoami::username);
+
+        let proxy_log = log.clone();
+        let proxy_jump = jump.clone();
+        let connection = unblock(move || connection::connect(&addr, port, proxy_jump.as_deref(), &proxy_log))
+            .await
+            .map_err(stage.fail())?;
+
+        // libssh2 blocks on the network in every call, including when the session is dropped,
+        // so the session only ever lives on blocking threads.
+        let stage = DiffStage::Handshake;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let session = unblock(move || {
+            let mut session = ssh2::Session::new()?;
+            session.set_timeout(connection::SESSION_TIMEOUT.as_millis() as u32);
+            session.set_tcp_stream(connection);
+            session.handshake().map(|()| session)
+        })
+        .await
+        .map_err(|err| NixError::SshHandshake { message: err.to_string() })
+        .map_err(stage.fail())?;
+
+        let stage = DiffStage::Authenticating;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let user = username.clone();
+        let session = unblock(move || session.userauth_agent(&user).map(|()| session))
+            .await
+            .map_err(|err| NixError::AgentAuth {
+                user: username.clone(),
+                message: err.to_string(),
+            })
+            .map_err(stage.fail())?;
+
+        let stage = DiffStage::OpeningSftp;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let (session, sftp) = unblock(move || session.sftp().map(|sftp| (session, sftp)))
+            .await
+            .map_err(|err| NixError::Sftp { message: err.to_string() })
+            .map_err(stage.fail())?;
+
+        let stage = DiffStage::ResolvingSystem;
+        yield Ok(DiffEvent::Stage(stage));
+
+        let toplevel = new_drv.clone();
+        let (system_drv, remote_deriver) = unblock(move || {
+            // The profile link itself points at the toplevel, going through `system/system`
+            // would end at the `system` file inside of it instead.
+            let system_drv = sftp
+                .realpath(Path::new("/nix/var/nix/profiles/system"))
+                .map(|system_drv| store_path_root(&system_drv));
+            // The derivers only matter if the paths differ, e.g. for content addressed systems.
+            let remote_deriver = system_drv
+                .as_ref()
+                .ok()
+                .filter(|system_drv| **system_drv != toplevel)
+                .and_then(|system_drv| remote_deriver(&session, system_drv));
+            drop(sftp);
+            drop(session);
+            system_drv.map(|system_drv| (system_drv, remote_deriver))
+        })
+        .await
+        .map_err(|err| NixError::Sftp { message: err.to_string() })
+        .map_err(stage.fail())?;
+
+        let local_deriver = match &remote_deriver {
+            Some(_) => local_deriver(&config, &new_drv, &log).await,
+            None => None,
+        };
+        let derivers = remote_deriver.as_deref().zip(local_deriver.as_deref());
+        if same_system(&system_drv, &new_drv, derivers) {
+            debug!("{node_name} already runs {system_drv:?}, skipping the diff");
+            let diff = NodeDiff::new(system_drv, new_drv, String::new(), true);
+            yield Ok(DiffEvent::Finished(finish(&config, &node_name, flake_rev, diff).await));
+            return;
+        }
+
+        // Only looked up now so a cached diff can never overrule the up to date check.
+        let key = DiffKey {
+            system_path: system_drv.clone(),
+            toplevel: new_drv.clone(),
+        };
+        if let Some(cached) = unblock(move || cache::load(&key)).await {
+            debug!("Using the cached diff of {system_drv:?} and {new_drv:?}");
+            yield Ok(DiffEvent::Finished(finish(&config, &node_name, flake_rev, cached).await));
+            return;
+        }
+
+        let stage = DiffStage::CopyingClosure;
+        yield Ok(DiffEvent::Stage(stage));
+
+        debug!("Copying {system_drv:?} from host");
+
+        // Paths built on the node aren't signed, the local store has to take them as they are.
+        let host = format!("{username}@{host}");
+        let store = format!("ssh://{host}");
+        let system_str = system_drv.to_string_lossy();
+        let mut copy_args = vec!["copy", "--no-check-sigs", "--from", &store, &system_str];
+        copy_args.extend(build_log::LOG_FORMAT_ARGS);
+        let copy_args = config.nix_args(&copy_args);
+        let ssh_opts = match &jump {
+            Some(jump) => format!("-p {port} -J {jump}"),
+            None => format!("-p {port}"),
+        };
+        let copy_closure = cmd("nix", &copy_args).env("NIX_SSHOPTS", ssh_opts);
+        let permit = job.acquire(JobKind::Copy).await;
+        let copy = log
+            .run(format!("nix {}", copy_args.join(" ")), copy_closure)
+            .await
+            .map_err(|err| NixError::spawn("nix", err))
+            .map_err(stage.fail())?;
+        if !copy.status.success() {
+            let stderr = build_log::plain_text(&copy.stderr);
+            yield Err(DiffError::new(stage, NixError::CopyClosure { host, stderr }));
+            return;
+        }
+        drop(permit);
+
+        let stage = DiffStage::Diffing;
+        yield Ok(DiffEvent::Stage(stage));
+
+        debug!("Diffing: {system_drv:?} against {new_drv:?}");
+
+        let nvd_description = format!("nvd diff {} {}", system_drv.display(), new_drv.display());
+        let nvd = log
+            .run(nvd_description, cmd!("nvd", "--color", "always", "diff", &system_drv, &new_drv))
+            .await
+            .map_err(|err| NixError::spawn("nvd", err))
+            .map_err(stage.fail())?;
+        if !nvd.status.success() {
+            yield Err(DiffError::new(stage, NixError::NvdFailed { stderr: nvd.stderr }));
+            return;
+        }
+
+        let diff = NodeDiff::new(system_drv, new_drv, nvd.stdout, false);
+        yield Ok(DiffEvent::Finished(finish(&config, &node_name, flake_rev, diff).await));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nix::scheduler::Scheduler;
+    use iced_futures::Executor;
+    use iced_futures::backend::native::tokio;
+    use std::fs;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::pin::pin;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Accepts SSH connections, answers them slowly with a banner and hangs up, so every
+    /// handshake spends `delay` twice on the network before it fails.
+    fn slow_ssh_endpoint(delay: Duration) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("a free port");
+        let port = listener.local_addr().expect("a bound port").port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().map_while(Result::ok) {
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let _ = stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n");
+                    thread::sleep(delay);
+                });
+            }
+        });
+        port
+    }
+
+    /// Times how late a small task runs on the UI's executor while nodes are diffed next to
+    /// it, which is the delay the UI sees for every message.
+    #[test]
+    #[ignore = "benchmark, needs a ~/.ssh/config; run with --ignored --nocapture"]
+    fn executor_latency_next_to_diffs() {
+        const NODES: usize = 32;
+        const FRAME: Duration = Duration::from_millis(16);
+
+        let port = slow_ssh_endpoint(Duration::from_millis(250));
+        let cluster_dir = std::env::temp_dir().join("checkit-latency-bench");
+        fs::create_dir_all(&cluster_dir).expect("a cluster directory");
+        fs::write(cluster_dir.join("flake.nix"), "{ }").expect("a cluster file");
+        let config = ClusterConfig {
+            cluster_path: cluster_dir,
+            ..ClusterConfig::default()
+        };
+
+        // The executor iced runs the UI's tasks on.
+        let executor: tokio::Executor = Executor::new().expect("a tokio runtime");
+        let scheduler = Scheduler::default();
+        let (finished, diffs_done) = mpsc::channel();
+        let start = Instant::now();
+        for idx in 0..NODES {
+            let prepared = PreparedNode {
+                target: Target {
+                    host: "127.0.0.1".to_owned(),
+                    source: AddressSource::IpAttr,
+                    port: Some(port),
+                    user: None,
+                    jump: None,
+                },
+                toplevel: PathBuf::from("/nix/store/00000000000000000000000000000000-system"),
+            };
+            let diff = run_diff(
+                config.clone(),
+                format!("node{idx}"),
+                scheduler.job(),
+                Some(prepared),
+            );
+            let finished = finished.clone();
+            Executor::spawn(&executor, async move {
+                let mut diff = pin!(diff);
+                let mut failed_at = None;
+                while let Some(event) = diff.next().await {
+                    if let Err(err) = event {
+                        failed_at = Some(err.stage);
+                    }
+                }
+                let _ = finished.send(failed_at);
+            });
+        }
+
+        let mut lateness = Vec::new();
+        let mut failed_at = Vec::new();
+        while failed_at.len() < NODES {
+            let spawned = Instant::now();
+            let (ran, ran_at) = mpsc::channel();
+            Executor::spawn(&executor, async move {
+                let _ = ran.send(spawned.elapsed());
+            });
+            lateness.push(ran_at.recv().expect("the task ran"));
+            thread::sleep(FRAME);
+            failed_at.extend(diffs_done.try_iter());
+        }
+        let total = start.elapsed();
+
+        // Every diff has to have waited on the endpoint for this to measure anything.
+        assert!(
+            failed_at
+                .iter()
+                .all(|stage| *stage == Some(DiffStage::Handshake)),
+            "diffs failed at {failed_at:?}"
+        );
+        lateness.sort();
+        let percentile = |p: usize| lateness[(lateness.len() - 1) * p / 100].as_millis();
+        println!(
+            "{NODES} diffs in {} ms, task lateness p50 {} ms, p99 {} ms, max {} ms",
+            total.as_millis(),
+            percentile(50),
+            percentile(99),
+            percentile(100),
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::nix::diff::DiffStage;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while evaluating, reaching or diffing a node.
+#[derive(Debug, Clone)]
+pub enum NixError {
+    ClusterPath { path: PathBuf, reason: String },
+    CommandMissing { program: String },
+    CommandFailed { program: String, message: String },
+    EvalFailed { attr: String, stderr: String },
+    AttributeMissing { attr: String, stderr: String },
+    InvalidOutput { expected: String, output: String },
+    BuildFailed { attr: String, stderr: String },
+    SshConfig { message: String },
+    NoAddress { node: String },
+    TcpConnect { addr: String, message: String },
+    SshHandshake { message: String },
+    AgentAuth { user: String, message: String },
+    Sftp { message: String },
+    CopyClosure { host: String, stderr: String },
+    NvdMissing,
+    NvdFailed { stderr: String },
+}
+
+impl NixError {
+    /// Classifies a failure to start `program`.
+    pub fn spawn(program: &str, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound if program == "nvd" => NixError::NvdMissing,
+            io::ErrorKind::NotFound => NixError::CommandMissing {
+                program: program.to_owned(),
+            },
+            _ => NixError::CommandFailed {
+                program: program.to_owned(),
+                message: err.to_string(),
+            },
+        }
+    }
+
+    /// Classifies a failed `nix eval` of `attr` by its stderr.
+    pub fn eval(attr: &str, stderr: String) -> Self {
+        let attr = attr.to_owned();
+        if stderr.contains("does not provide attribute")
+            || stderr.contains("attribute '") && stderr.contains("missing")
+        {
+            NixError::AttributeMissing { attr, stderr }
+        } else {
+            NixError::EvalFailed { attr, stderr }
+        }
+    }
+
+    /// Output of the failed command, if the error came from one.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            NixError::EvalFailed { stderr, .. }
+            | NixError::AttributeMissing { stderr, .. }
+            | NixError::BuildFailed { stderr, .. }
+            | NixError::CopyClosure { stderr, .. }
+            | NixError::NvdFailed { stderr } => Some(stderr),
+            NixError::InvalidOutput { output, .. } => Some(output),
+            _ => None,
+        }
+    }
+
+    /// A suggestion on how to fix the error.
+    pub fn hint(&self) -> String {
+        match self {
+            NixError::ClusterPath { .. } => {
+                "Point the cluster path at a flake.nix, a hive.nix, an inventory or the \
+                 directory containing it, or at a directory of NixOS configurations."
+                    .to_owned()
+            }
+            NixError::CommandMissing { program } => {
+                format!("Install `{program}` and make sure it's in your PATH.")
+            }
+            NixError::CommandFailed { program, .. } => {
+                format!("Check that `{program}` can be run by your user.")
+            }
+            NixError::EvalFailed { attr, .. } => {
+                format!(
+                    "Run `nix eval {attr} --show-trace` in the cluster directory for the full trace."
+                )
+            }
+            NixError::AttributeMissing { .. } => {
+                "Check the node name, the nodes attribute and the IP address attribute path."
+                    .to_owned()
+            }
+            NixError::InvalidOutput { expected, .. } => {
+                format!("The attribute must evaluate to {expected}.")
+            }
+            NixError::BuildFailed { attr, .. } => {
+                format!(
+                    "Run `nix build {attr} -L` in the cluster directory for the full build log."
+                )
+            }
+            NixError::SshConfig { .. } => "Fix the syntax error in ~/.ssh/config.".to_owned(),
+            NixError::NoAddress { .. } => "Set the node's IP address attribute, \
+                 deployment.targetHost, an interface address or networking.fqdn, or give it a \
+                 HostName in ~/.ssh/config."
+                .to_owned(),
+            NixError::TcpConnect { addr, .. } => {
+                format!(
+                    "Make sure the node is up and reachable at {addr}, and that the SSH port is right."
+                )
+            }
+            NixError::SshHandshake { .. } => {
+                "Make sure an SSH server is listening on the node's SSH port.".to_owned()
+            }
+            NixError::AgentAuth { user, .. } => {
+                format!(
+                    "Make sure ssh-agent is running and holds a key authorized for {user} (`ssh-add -l`)."
+                )
+            }
+            NixError::Sftp { .. } => {
+                "Enable the SFTP subsystem in the node's sshd config.".to_owned()
+            }
+            NixError::CopyClosure { host, .. } => {
+                format!(
+                    "Make sure `ssh {host}` works non-interactively and you're a trusted user of the local nix daemon."
+                )
+            }
+            NixError::NvdMissing => {
+                "Install nvd, e.g. with `nix profile install nixpkgs#nvd`.".to_owned()
+            }
+            NixError::NvdFailed { .. } => {
+                "Make sure both system closures are present in the local nix store.".to_owned()
+            }
+        }
+    }
+}
+
+impl Display for NixError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixError::ClusterPath { path, reason } => {
+                write!(f, "Invalid cluster path {}: {reason}", path.display())
+            }
+            NixError::CommandMissing { program } => write!(f, "`{program}` is not installed"),
+            NixError::CommandFailed { program, message } => {
+                write!(f, "Couldn't run `{program}`: {message}")
+            }
+            NixError::EvalFailed { attr, .. } => write!(f, "Couldn't evaluate {attr}"),
+            NixError::AttributeMissing { attr, .. } => write!(f, "Attribute {attr} doesn't exist"),
+            NixError::InvalidOutput { expected, .. } => {
+                write!(f, "Unexpected nix output, expected {expected}")
+            }
+            NixError::BuildFailed { attr, .. } => write!(f, "Couldn't build {attr}"),
+            NixError::SshConfig { message } => write!(f, "Couldn't read SSH config: {message}"),
+            NixError::NoAddress { node } => write!(f, "Found no address for {node}"),
+            NixError::TcpConnect { addr, message } => {
+                write!(f, "Couldn't connect to {addr}: {message}")
+            }
+            NixError::SshHandshake { message } => write!(f, "SSH handshake failed: {message}"),
+            NixError::AgentAuth { user, message } => {
+                write!(f, "SSH agent authentication as {user} failed: {message}")
+            }
+            NixError::Sftp { message } => write!(f, "SFTP failed: {message}"),
+            NixError::CopyClosure { host, .. } => {
+                write!(f, "Couldn't copy the system closure from {host}")
+            }
+            NixError::NvdMissing => write!(f, "nvd is not installed"),
+            NixError::NvdFailed { .. } => write!(f, "nvd couldn't diff the two systems"),
+        }
+    }
+}
+
+impl std::error::Error for NixError {}
+
+/// A [`NixError`] together with the stage of the diff it happened in.
+#[derive(Debug, Clone)]
+pub struct DiffError {
+    pub stage: DiffStage,
+    pub error: NixError,
+}
+
+impl DiffError {
+    pub fn new(stage: DiffStage, error: NixError) -> Self {
+        Self { stage, error }
+    }
+
+    pub fn hint(&self) -> String {
+        self.error.hint()
+    }
+
+    pub fn stderr(&self) -> Option<&str> {
+        self.error.stderr()
+    }
+}
+
+impl Display for DiffError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.stage.label(), self.error)
+    }
+}
+
+impl std::error::Error for DiffError {}
@@ -0,0 +1,228 @@
+//! Evaluating nodes with [nix-eval-jobs](https://github.com/nix-community/nix-eval-jobs).
+//!
+//! nix-eval-jobs evaluates the nodes in parallel worker processes and prints a JSON line for
+//! each node as soon as it's done, so results show up while the rest is still evaluating. A
+//! node that fails to evaluate doesn't stop the others.
+
+use crate::nix::batch::{BatchNode, NodeEvaluation};
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use crate::nix::source::{self, AddressSource, EvaluatedNode, Target};
+use crate::nix::{ClusterConfig, EvalJobs, nix_string};
+use duct::cmd;
+use futures::channel::mpsc;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Meta attributes the node's target is smuggled out in, nix-eval-jobs only reports derivations.
+// Nulls aren't valid meta values and are left out by nix-eval-jobs.
+const HOST_META: &str = "checkitHost";
+const PORT_META: &str = "checkitPort";
+const USER_META: &str = "checkitUser";
+const JUMP_META: &str = "checkitJump";
+const HOST_SOURCE_META: &str = "checkitHostSource";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JobLine {
+    attr: String,
+    drv_path: Option<String>,
+    error: Option<String>,
+    #[serde(default)]
+    meta: HashMap<String, Value>,
+}
+
+/// An attribute set of every node's system derivation, carrying its target in the meta.
+///
+/// `nodes_expression` evaluates to the attribute set of all nodes.
+fn expression(config: &ClusterConfig, nodes_expression: &str, nodes: &[BatchNode]) -> String {
+    let mut expr = format!("let nodes = {nodes_expression}; in {{");
+    for node in nodes {
+        let _ = write!(
+            expr,
+            " {} = let node = {}; in node.system // {{ meta = (node.system.meta or {{ }}) // \
+             {{ {HOST_META} = node.host; {HOST_SOURCE_META} = node.hostSource; {PORT_META} = node.port; {USER_META} = node.user; \
+             {JUMP_META} = node.jump or null; }}; }};",
+            nix_string(&node.name),
+            source::node_expression(config, &node.name, &node.ip_attr)
+        );
+    }
+    expr.push_str(" }");
+    expr
+}
+
+/// Reads the result of a node from a line nix-eval-jobs printed.
+pub fn parse_line(line: &str) -> Option<NodeEvaluation> {
+    let job: JobLine = serde_json::from_str(line).ok()?;
+
+    let result = match (job.error, job.drv_path) {
+        (Some(error), _) => Err(error),
+        (None, Some(drv_path)) => {
+            let meta_str = |name| {
+                job.meta
+                    .get(name)
+                    .and_then(Value::as_str)
+                    .map(ToOwned::to_owned)
+            };
+            let (host, source) = source::resolve_address(
+                &job.attr,
+                meta_str(HOST_META),
+                job.meta
+                    .get(HOST_SOURCE_META)
+                    .and_then(|source| AddressSource::deserialize(source).ok()),
+            );
+            Ok(EvaluatedNode {
+                target: Target {
+                    host,
+                    source,
+                    port: job
+                        .meta
+                        .get(PORT_META)
+                        .and_then(Value::as_u64)
+                        .and_then(|port| port.try_into().ok()),
+                    user: meta_str(USER_META),
+                    jump: meta_str(JUMP_META),
+                },
+                drv_path,
+            })
+        }
+        (None, None) => Err("nix-eval-jobs didn't report a system derivation".to_owned()),
+    };
+
+    Some(NodeEvaluation {
+        node: job.attr,
+        result,
+    })
+}
+
+/// Evaluates `nodes` with nix-eval-jobs, sending every line it prints to `results` as soon
+/// as the node is done.
+///
+/// Only failing to run nix-eval-jobs at all is an error, failed nodes are part of the result.
+pub async fn evaluate(
+    config: &ClusterConfig,
+    settings: EvalJobs,
+    nodes_expression: &str,
+    nodes: &[BatchNode],
+    log: &CommandLog,
+    results: mpsc::UnboundedSender<String>,
+) -> Result<Vec<NodeEvaluation>, NixError> {
+    let cluster_dir = config.cluster_dir()?;
+    let expr = expression(config, nodes_expression, nodes);
+    let workers = settings.workers.max(1).to_string();
+    let max_memory = settings.max_memory_mib.to_string();
+    // getFlake needs an impure evaluation to accept the unlocked cluster directory.
+    let args = config.nix_eval_jobs_args(&[
+        "--impure",
+        "--meta",
+        "--workers",
+        &workers,
+        "--max-memory-size",
+        &max_memory,
+        "--expr",
+        &expr,
+    ]);
+
+    let output = log
+        .run_streaming(
+            format!("nix-eval-jobs {}", args.join(" ")),
+            cmd("nix-eval-jobs", &args).dir(cluster_dir),
+            results,
+        )
+        .await
+        .map_err(|err| NixError::spawn("nix-eval-jobs", err))?;
+
+    if !output.status.success() {
+        return Err(NixError::eval(config.attr_root(), output.stderr));
+    }
+
+    Ok(output.stdout.lines().filter_map(parse_line).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(line: &str) -> Target {
+        let evaluation = parse_line(line).expect("a job line");
+        evaluation.result.expect("an evaluated node").target
+    }
+
+    #[test]
+    fn parses_evaluated_node() {
+        let evaluation = parse_line(
+            r#"{"attr":"web","attrPath":["web"],"drvPath":"/nix/store/aaaa-nixos-system-web.drv","meta":{"checkitHost":"10.0.0.2","checkitHostSource":"target-host","checkitPort":2222,"checkitUser":"deploy","checkitJump":"bastion:22"},"name":"nixos-system-web","outputs":{"out":"/nix/store/bbbb-nixos-system-web"},"system":"x86_64-linux"}"#,
+        )
+        .expect("a job line");
+
+        assert_eq!(evaluation.node, "web");
+        let evaluated = evaluation.result.expect("an evaluated node");
+        assert_eq!(evaluated.drv_path, "/nix/store/aaaa-nixos-system-web.drv");
+        assert_eq!(
+            evaluated.target,
+            Target {
+                host: "10.0.0.2".to_owned(),
+                source: AddressSource::TargetHost,
+                port: Some(2222),
+                user: Some("deploy".to_owned()),
+                jump: Some("bastion:22".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_failed_node() {
+        let evaluation = parse_line(
+            r#"{"attr":"db","attrPath":["db"],"error":"error: attribute 'ip' missing"}"#,
+        )
+        .expect("a job line");
+
+        assert_eq!(evaluation.node, "db");
+        assert_eq!(
+            evaluation.result.err().as_deref(),
+            Some("error: attribute 'ip' missing")
+        );
+    }
+
+    #[test]
+    fn missing_meta_falls_back_to_node_name() {
+        let target = target(
+            r#"{"attr":"db","attrPath":["db"],"drvPath":"/nix/store/aaaa-nixos-system-db.drv"}"#,
+        );
+        assert_eq!(
+            target,
+            Target {
+                host: "db".to_owned(),
+                source: AddressSource::SshConfig,
+                port: None,
+                user: None,
+                jump: None,
+            }
+        );
+    }
+
+    #[test]
+    fn host_without_source_is_the_ip_attribute() {
+        let target = target(
+            r#"{"attr":"db","drvPath":"/nix/store/aaaa-nixos-system-db.drv","meta":{"checkitHost":"db.example.com","checkitPort":70000}}"#,
+        );
+        assert_eq!(target.host, "db.example.com");
+        assert_eq!(target.source, AddressSource::IpAttr);
+        // Out of range ports are dropped rather than truncated.
+        assert_eq!(target.port, None);
+    }
+
+    #[test]
+    fn node_without_derivation_fails() {
+        let evaluation = parse_line(r#"{"attr":"db"}"#).expect("a job line");
+        assert!(evaluation.result.is_err());
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert!(parse_line("warning: Git tree '/cluster' is dirty").is_none());
+        assert!(parse_line(r#"{"drvPath":"/nix/store/aaaa.drv"}"#).is_none());
+    }
+}
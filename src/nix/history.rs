@@ -0,0 +1,114 @@
+//! Every diff a node went through, to look back at how it drifted over time.
+//!
+//! Each node of a cluster has a JSON Lines file in `$XDG_DATA_HOME/checkit/history`, with
+//! one entry appended per finished diff.
+
+use crate::nix::diff::NodeDiff;
+use crate::nix::process::CommandLog;
+use anyhow::Context;
+use duct::cmd;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the unix epoch at which the node was diffed.
+    pub checked_at: u64,
+    /// Git revision of the cluster the node was diffed against, see [`flake_rev`].
+    pub flake_rev: Option<String>,
+    #[serde(flatten)]
+    pub diff: NodeDiff,
+}
+
+/// History file of `node` in the cluster at `cluster_path`.
+fn history_path(cluster_path: &Path, node: &str) -> Option<PathBuf> {
+    // Flattens the cluster path into a single directory name that maps back to it.
+    let cluster = cluster_path
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    let node = node.replace('%', "%25").replace('/', "%2F");
+    dirs::data_dir().map(|dir| {
+        dir.join("checkit")
+            .join("history")
+            .join(cluster)
+            .join(format!("{node}.jsonl"))
+    })
+}
+
+/// Appends `entry` to the history of `node`. Failing to is only logged.
+///
+/// Blocks on the file system.
+pub fn record(cluster_path: &Path, node: &str, entry: &HistoryEntry) {
+    let result = history_path(cluster_path, node)
+        .context("No data directory available")
+        .and_then(|path| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Couldn't create {}", dir.display()))?;
+            }
+            let mut line = serde_json::to_string(entry).context("Couldn't serialize entry")?;
+            line.push('\n');
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+                .with_context(|| format!("Couldn't write {}", path.display()))
+        });
+
+    if let Err(err) = result {
+        warn!("Failed to record diff history of {node}: {err:?}");
+    }
+}
+
+/// The history of `node`, newest first. Entries that can't be read are skipped.
+///
+/// Blocks on the file system.
+pub fn load(cluster_path: &Path, node: &str) -> Vec<HistoryEntry> {
+    let Some(path) = history_path(cluster_path, node).filter(|path| path.exists()) else {
+        return Vec::new();
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(raw) => raw
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect(),
+        Err(err) => {
+            warn!("Couldn't read {}: {err}", path.display());
+            Vec::new()
+        }
+    }
+}
+
+/// The git revision the cluster in `cluster_dir` is at, suffixed with `-dirty` if it has
+/// uncommitted changes. None if it isn't a git repository.
+pub async fn flake_rev(cluster_dir: &Path, log: &CommandLog) -> Option<String> {
+    let rev = log
+        .run(
+            "git rev-parse HEAD".to_owned(),
+            cmd!("git", "rev-parse", "HEAD").dir(cluster_dir),
+        )
+        .await
+        .ok()
+        .filter(|rev| rev.status.success())?;
+    let status = log
+        .run(
+            "git status --porcelain".to_owned(),
+            cmd!("git", "status", "--porcelain").dir(cluster_dir),
+        )
+        .await
+        .ok()
+        .filter(|status| status.status.success())?;
+
+    if status.stdout.is_empty() {
+        Some(rev.stdout)
+    } else {
+        Some(format!("{}-dirty", rev.stdout))
+    }
+}
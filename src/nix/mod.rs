@@ -0,0 +1,316 @@
+//! Evaluating, building and diffing the nodes of a nix cluster, independent of any UI.
+
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use crate::nix::source::{SourceKind, colmena};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub mod batch;
+pub mod build_log;
+pub mod cache;
+pub mod connection;
+pub mod diff;
+pub mod error;
+pub mod eval_jobs;
+pub mod history;
+pub mod process;
+pub mod progress;
+pub mod scheduler;
+pub mod source;
+
+pub const DEFAULT_IP_ATTR: &str = "config.base.primaryIP.address";
+
+/// Where a cluster lives and how to evaluate and reach its nodes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Path to the cluster's flake.nix, hive.nix or inventory, or the directory containing it
+    /// or the cluster's NixOS configurations.
+    pub cluster_path: PathBuf,
+    pub source: SourceKind,
+    /// Flake reference to evaluate flake sources from, e.g. `git+file:///repo?rev=<rev>` or
+    /// `path:/repo?dir=cluster`. Empty for the cluster directory.
+    pub flake_ref: String,
+    /// Attribute path of a node's IP address, relative to the node. The first address source
+    /// tried, see [`source::AddressSource`].
+    pub ip_attr: String,
+    /// Flake output attribute containing the nodes. Empty for the source's default.
+    pub attr_root: String,
+    /// Flags and settings for every nix command run for the cluster.
+    pub nix_flags: NixFlags,
+    /// Any other arguments passed to every nix and nix-eval-jobs invocation.
+    pub nix_args: Vec<String>,
+    pub ssh: SshOverrides,
+    /// Evaluate nodes in batches with nix-eval-jobs instead of a single `nix eval`.
+    pub eval_jobs: Option<EvalJobs>,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            cluster_path: PathBuf::new(),
+            source: SourceKind::default(),
+            flake_ref: String::new(),
+            ip_attr: DEFAULT_IP_ATTR.to_owned(),
+            attr_root: String::new(),
+            nix_flags: NixFlags::default(),
+            nix_args: Vec::new(),
+            ssh: SshOverrides::default(),
+            eval_jobs: None,
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// The file defining the cluster, even if the cluster path points at its directory.
+    ///
+    /// In a directory that's its flake.nix, or its hive.nix if it has no flake. A directory
+    /// with neither is a directory of NixOS configurations and stays as it is.
+    pub fn cluster_file(&self) -> PathBuf {
+        if !self.cluster_path.is_dir() {
+            return self.cluster_path.clone();
+        }
+
+        [
+            self.cluster_path.join("flake.nix"),
+            self.cluster_path.join(colmena::HIVE_FILE),
+        ]
+        .into_iter()
+        .find(|file| file.exists())
+        .unwrap_or_else(|| self.cluster_path.clone())
+    }
+
+    /// Directory nix commands of this cluster are run in.
+    pub fn cluster_dir(&self) -> Result<PathBuf, NixError> {
+        let file = self.cluster_file();
+        if file.is_dir() {
+            return Ok(file);
+        }
+        if !file.is_file() {
+            return Err(NixError::ClusterPath {
+                path: self.cluster_path.clone(),
+                reason: "Nothing found at this location".to_owned(),
+            });
+        }
+
+        file.parent()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| NixError::ClusterPath {
+                path: self.cluster_path.clone(),
+                reason: "Cluster path file didn't have a parent folder".to_owned(),
+            })
+    }
+
+    /// Arguments for a nix invocation, followed by the cluster's nix flags and extra
+    /// arguments.
+    pub fn nix_args(&self, args: &[&str]) -> Vec<String> {
+        let flags = &self.nix_flags;
+        let mut all: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+        if flags.impure {
+            all.push("--impure".to_owned());
+        }
+        if flags.accept_flake_config {
+            all.push("--accept-flake-config".to_owned());
+        }
+        for (input, flake_ref) in &flags.override_inputs {
+            all.extend([
+                "--override-input".to_owned(),
+                input.clone(),
+                flake_ref.clone(),
+            ]);
+        }
+        all.extend(flags.option_args("--option"));
+        all.extend(self.nix_args.iter().cloned());
+        all
+    }
+
+    /// Like [`ClusterConfig::nix_args`], for nix-eval-jobs.
+    ///
+    /// It always evaluates impurely and can't override inputs of the flakes it gets, see
+    /// [`source::nodes_expression`].
+    pub fn nix_eval_jobs_args(&self, args: &[&str]) -> Vec<String> {
+        let mut all: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+        if self.nix_flags.accept_flake_config {
+            all.extend(["--option", "accept-flake-config", "true"].map(ToOwned::to_owned));
+        }
+        all.extend(self.nix_flags.option_args("--option"));
+        all.extend(self.nix_args.iter().cloned());
+        all
+    }
+
+    /// Like [`ClusterConfig::nix_args`], for colmena.
+    ///
+    /// Only the flags that apply to a hive.nix are passed, extra arguments aren't as colmena
+    /// doesn't take nix's.
+    pub fn colmena_args(&self, args: &[&str]) -> Vec<String> {
+        let mut all: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+        if self.nix_flags.impure {
+            all.push("--impure".to_owned());
+        }
+        all.extend(self.nix_flags.option_args("--nix-option"));
+        all
+    }
+}
+
+/// Flags for every nix command run for a cluster.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NixFlags {
+    pub impure: bool,
+    pub accept_flake_config: bool,
+    /// Flake inputs to override, by input path.
+    pub override_inputs: BTreeMap<String, String>,
+    /// nix settings, by name.
+    pub options: BTreeMap<String, String>,
+}
+
+impl NixFlags {
+    /// The settings as `<option_flag> <name> <value>` arguments.
+    fn option_args<'a>(&'a self, option_flag: &'a str) -> impl Iterator<Item = String> + 'a {
+        self.options
+            .iter()
+            .flat_map(move |(name, value)| [option_flag.to_owned(), name.clone(), value.clone()])
+    }
+}
+
+/// SSH connection settings that take precedence over `~/.ssh/config`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshOverrides {
+    pub user: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// Settings of the nix-eval-jobs evaluator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EvalJobs {
+    /// Number of worker processes evaluating nodes in parallel.
+    pub workers: usize,
+    /// Memory in MiB a worker may use before it's replaced by a fresh one.
+    pub max_memory_mib: usize,
+}
+
+impl Default for EvalJobs {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_memory_mib: 4096,
+        }
+    }
+}
+
+/// Quotes `value` as a nix string.
+pub fn nix_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
+/// Builds all `drv_paths` with one `nix build`, returning the output of each derivation.
+///
+/// `what` names the derivations in errors.
+pub async fn build_derivations(
+    config: &ClusterConfig,
+    drv_paths: &[&str],
+    what: &str,
+    log: &CommandLog,
+) -> Result<HashMap<String, PathBuf>, NixError> {
+    #[derive(Deserialize)]
+    struct BuildResult {
+        #[serde(rename = "drvPath")]
+        drv_path: String,
+        outputs: HashMap<String, PathBuf>,
+    }
+
+    let cluster_dir = config.cluster_dir()?;
+    let installables: Vec<_> = drv_paths.iter().map(|drv| format!("{drv}^out")).collect();
+    let mut build_args = vec!["build", "--json", "--no-link", "--keep-going"];
+    build_args.extend(installables.iter().map(String::as_str));
+    build_args.extend(build_log::LOG_FORMAT_ARGS);
+    let build_args = config.nix_args(&build_args);
+
+    let build = log
+        .run(
+            format!("nix {}", build_args.join(" ")),
+            duct::cmd("nix", &build_args).dir(cluster_dir),
+        )
+        .await
+        .map_err(|err| NixError::spawn("nix", err))?;
+    if !build.status.success() {
+        return Err(NixError::BuildFailed {
+            attr: what.to_owned(),
+            stderr: build_log::plain_text(&build.stderr),
+        });
+    }
+
+    let results: Vec<BuildResult> =
+        serde_json::from_str(&build.stdout).map_err(|_| NixError::InvalidOutput {
+            expected: "a list of build results".to_owned(),
+            output: build.stdout.clone(),
+        })?;
+
+    Ok(results
+        .into_iter()
+        .filter_map(|mut result| Some((result.drv_path, result.outputs.remove("out")?)))
+        .collect())
+}
+
+/// Runs `nix eval --json --expr <expr>` in the cluster directory, returning the JSON output.
+///
+/// The evaluation is impure so the expression can use the NIX_PATH. `what` names the
+/// expression in errors.
+pub async fn nix_eval_expr(
+    config: &ClusterConfig,
+    expr: &str,
+    what: &str,
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    let cluster_dir = config.cluster_dir()?;
+    let args = config.nix_args(&["eval", "--impure", "--json", "--expr", expr]);
+
+    let output = log
+        .run(
+            format!("nix {}", args.join(" ")),
+            duct::cmd("nix", args).dir(cluster_dir),
+        )
+        .await
+        .map_err(|err| NixError::spawn("nix", err))?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(NixError::eval(what, output.stderr))
+    }
+}
+
+/// Runs `nix eval <attr> --json` in the cluster directory, returning the JSON output.
+///
+/// `extra_args` are appended after `--json`, e.g. `--apply` expressions.
+pub async fn nix_eval(
+    config: &ClusterConfig,
+    attr: &str,
+    extra_args: &[&str],
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    let cluster_dir = config.cluster_dir()?;
+    let args = config.nix_args(&[&["eval", attr, "--json"], extra_args].concat());
+
+    let output = log
+        .run(
+            format!("nix {}", args.join(" ")),
+            duct::cmd("nix", args).dir(cluster_dir),
+        )
+        .await
+        .map_err(|err| NixError::spawn("nix", err))?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(NixError::eval(attr, output.stderr))
+    }
+}
@@ -0,0 +1,144 @@
+use duct::{Expression, Handle};
+use futures::channel::mpsc;
+use log::warn;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+/// Output of an external command that ran to completion.
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Receives the stderr of every command run through it, line by line, as it's written.
+#[derive(Debug, Clone)]
+pub struct CommandLog(Option<mpsc::UnboundedSender<String>>);
+
+impl CommandLog {
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded();
+        (Self(Some(sender)), receiver)
+    }
+
+    /// A log that drops everything. Stderr is still captured for errors.
+    pub fn disabled() -> Self {
+        Self(None)
+    }
+
+    pub fn line(&self, line: impl Into<String>) {
+        if let Some(sender) = &self.0 {
+            // The receiving end going away just means nobody is interested in the log anymore.
+            let _ = sender.unbounded_send(line.into());
+        }
+    }
+
+    /// Runs a command, streaming its stderr into the log and capturing both outputs
+    /// regardless of the exit status.
+    ///
+    /// `description` is logged as the command line before the command starts.
+    /// Dropping the returned future before it completes kills the command.
+    pub async fn run(
+        &self,
+        description: String,
+        expression: Expression,
+    ) -> io::Result<CommandOutput> {
+        self.run_inner(description, expression, None).await
+    }
+
+    /// Like [`Self::run`], additionally sending every line the command writes to stdout to
+    /// `stdout_lines` as soon as it's written.
+    pub async fn run_streaming(
+        &self,
+        description: String,
+        expression: Expression,
+        stdout_lines: mpsc::UnboundedSender<String>,
+    ) -> io::Result<CommandOutput> {
+        self.run_inner(description, expression, Some(stdout_lines))
+            .await
+    }
+
+    async fn run_inner(
+        &self,
+        description: String,
+        expression: Expression,
+        stdout_lines: Option<mpsc::UnboundedSender<String>>,
+    ) -> io::Result<CommandOutput> {
+        self.line(format!("$ {description}"));
+
+        let (stderr_reader, stderr_writer) = os_pipe::pipe()?;
+        let (stdout_reader, stdout_writer) = os_pipe::pipe()?;
+        // The expression owns our copies of the pipes' write ends and has to be dropped right
+        // after starting, otherwise the readers below never see EOF.
+        let handle = Arc::new(
+            expression
+                .stderr_file(stderr_writer)
+                .stdout_file(stdout_writer)
+                .unchecked()
+                .start()?,
+        );
+        let kill_guard = KillOnDrop(handle.clone());
+
+        let log = self.clone();
+        // Both pipes are drained at the same time, a command filling one of them while we wait
+        // on the other would never finish.
+        let stdout = unblock(move || {
+            read_lines(stdout_reader, |line| {
+                if let Some(sender) = &stdout_lines {
+                    let _ = sender.unbounded_send(line.to_owned());
+                }
+            })
+        });
+        let stderr = unblock(move || {
+            let stderr = read_lines(stderr_reader, |line| log.line(line));
+            handle.wait().map(|output| (output.status, stderr))
+        });
+        let (stdout, output) = futures::join!(stdout, stderr);
+        drop(kill_guard);
+        output.map(|(status, stderr)| CommandOutput {
+            status,
+            stdout: stdout.trim_end().to_owned(),
+            stderr,
+        })
+    }
+}
+
+/// Reads `reader` to the end, calling `on_line` for every line and returning all of it.
+fn read_lines(reader: impl Read, mut on_line: impl FnMut(&str)) -> String {
+    let mut all = String::new();
+    for line in BufReader::new(reader).split(b'\n') {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = String::from_utf8_lossy(&line);
+        all.push_str(&line);
+        all.push('\n');
+        on_line(&line);
+    }
+    all
+}
+
+/// Runs blocking `work` on a shared thread pool, so the executor polling the returned future
+/// stays free for other tasks.
+///
+/// The pool reuses idle threads and never grows beyond `BLOCKING_MAX_THREADS`, 500 by default.
+/// Unlike tokio's `spawn_blocking` it works on any executor, the CLI drives diffs with
+/// `futures::executor`.
+pub async fn unblock<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    blocking::unblock(work).await
+}
+
+/// Kills a command that is still running when the guard is dropped.
+struct KillOnDrop(Arc<Handle>);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait()
+            && let Err(err) = self.0.kill()
+        {
+            warn!("Couldn't kill cancelled command: {err}");
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::nix::build_log::{BuildEvent, BuildProgress};
+use crate::nix::diff::DiffStage;
+use std::time::{Duration, Instant};
+
+/// How far a node diff got, weighted by how long its stages usually take.
+#[derive(Debug)]
+pub struct DiffProgress {
+    started: Instant,
+    stage: DiffStage,
+    stage_started: Instant,
+    /// Nix activity of the current stage, if it runs a nix command reporting progress.
+    nix: BuildProgress,
+}
+
+impl Default for DiffProgress {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            stage: DiffStage::default(),
+            stage_started: now,
+            nix: BuildProgress::default(),
+        }
+    }
+}
+
+impl DiffProgress {
+    /// Progress only starts being extrapolated once this share of the work is done.
+    const MIN_FRACTION_FOR_ETA: f32 = 0.05;
+
+    pub fn stage_started(&mut self, stage: DiffStage) {
+        self.stage = stage;
+        self.stage_started = Instant::now();
+        self.nix = BuildProgress::default();
+    }
+
+    pub fn nix_event(&mut self, event: &BuildEvent) {
+        self.nix.apply(event);
+    }
+
+    pub fn stage(&self) -> DiffStage {
+        self.stage
+    }
+
+    /// Progress of the nix command run in the current stage.
+    pub fn nix(&self) -> &BuildProgress {
+        &self.nix
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn stage_elapsed(&self) -> Duration {
+        self.stage_started.elapsed()
+    }
+
+    /// Share of the current stage that is done, as far as it can be told.
+    fn stage_fraction(&self) -> f32 {
+        let (done, expected) = match self.stage {
+            DiffStage::BuildingToplevel => {
+                let (builds_done, builds_expected) = self.nix.builds();
+                let (downloads_done, downloads_expected) = self.nix.downloads();
+                (
+                    builds_done + downloads_done,
+                    builds_expected + downloads_expected,
+                )
+            }
+            DiffStage::CopyingClosure => self.nix.bytes_copied(),
+            _ => (0, 0),
+        };
+
+        if expected == 0 {
+            0.
+        } else {
+            (done as f32 / expected as f32).min(1.)
+        }
+    }
+
+    /// Overall progress of the diff from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        let completed: u32 = DiffStage::ALL
+            .iter()
+            .take_while(|stage| **stage != self.stage)
+            .map(|stage| stage.weight())
+            .sum();
+        let current = self.stage.weight() as f32 * self.stage_fraction();
+
+        (completed as f32 + current) / DiffStage::TOTAL_WEIGHT as f32
+    }
+
+    /// Estimated time until the diff finishes, extrapolated from the progress so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction();
+        (fraction >= Self::MIN_FRACTION_FOR_ETA)
+            .then(|| self.elapsed().mul_f32((1. - fraction) / fraction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fraction_counts_the_stages_before_the_current_one() {
+        let expected = [0, 8, 9, 59, 60, 62, 63, 64, 65, 66, 96];
+        let mut progress = DiffProgress::default();
+        for (stage, expected) in DiffStage::ALL.into_iter().zip(expected) {
+            progress.stage_started(stage);
+            assert_eq!(progress.fraction(), expected as f32 / 100., "{stage:?}");
+        }
+    }
+
+    #[test]
+    fn fraction_includes_the_nix_progress_of_the_current_stage() {
+        let mut progress = DiffProgress::default();
+        progress.stage_started(DiffStage::CopyingClosure);
+        for line in [
+            r#"@nix {"action":"start","fields":["/nix/store/aaaa-foo","ssh://root@node","local"],"id":1,"level":3,"parent":0,"text":"copying path '/nix/store/aaaa-foo' from 'ssh://root@node'","type":100}"#,
+            r#"@nix {"action":"result","fields":[250,1000,0,0],"id":1,"type":105}"#,
+        ] {
+            progress.nix_event(&BuildEvent::parse(line).expect("a nix log line"));
+        }
+
+        // A quarter of the closure's 30 after the 66 of the stages before it.
+        assert_eq!(progress.fraction(), 0.735);
+    }
+
+    #[test]
+    fn eta_extrapolates_the_elapsed_time() {
+        let mut progress = DiffProgress::default();
+        assert_eq!(progress.eta(), None, "too early to extrapolate");
+
+        progress.stage_started(DiffStage::Diffing);
+        progress.started -= Duration::from_secs(96);
+        let eta = progress.eta().expect("an ETA");
+
+        // 96 of the 100 parts took 96s, so 4 parts are left.
+        assert!(
+            (Duration::from_millis(3900)..Duration::from_millis(4100)).contains(&eta),
+            "{eta:?}"
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Limits how many diffs evaluate, build and copy at the same time.
+//!
+//! Every diff is a [`Job`] that has to acquire a [`Permit`] of the matching [`JobKind`] before
+//! running one of the heavy nix commands. Jobs waiting for a permit are served in priority
+//! order, which can be changed while they wait.
+
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Eval,
+    Build,
+    Copy,
+}
+
+impl JobKind {
+    pub const ALL: [JobKind; 3] = [JobKind::Eval, JobKind::Build, JobKind::Copy];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            JobKind::Eval => "eval",
+            JobKind::Build => "build",
+            JobKind::Copy => "copy",
+        }
+    }
+}
+
+/// How many jobs may run each kind of work at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobLimits {
+    pub eval: usize,
+    pub build: usize,
+    pub copy: usize,
+}
+
+impl Default for JobLimits {
+    fn default() -> Self {
+        Self {
+            eval: 4,
+            build: 2,
+            copy: 2,
+        }
+    }
+}
+
+impl JobLimits {
+    pub fn get(&self, kind: JobKind) -> usize {
+        match kind {
+            JobKind::Eval => self.eval,
+            JobKind::Build => self.build,
+            JobKind::Copy => self.copy,
+        }
+    }
+
+    pub fn set(&mut self, kind: JobKind, limit: usize) {
+        let slot = match kind {
+            JobKind::Eval => &mut self.eval,
+            JobKind::Build => &mut self.build,
+            JobKind::Copy => &mut self.copy,
+        };
+        *slot = limit;
+    }
+}
+
+pub type JobId = u64;
+
+struct Waiter {
+    job: JobId,
+    kind: JobKind,
+    ready: oneshot::Sender<Permit>,
+}
+
+#[derive(Default)]
+struct State {
+    limits: JobLimits,
+    next_id: JobId,
+    /// Registered jobs by priority, highest first.
+    order: Vec<JobId>,
+    running: [usize; JobKind::ALL.len()],
+    waiting: Vec<Waiter>,
+}
+
+impl State {
+    fn rank(&self, job: JobId) -> usize {
+        self.order
+            .iter()
+            .position(|id| *id == job)
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Jobs waiting for a permit of `kind`, in the order they'll get one.
+    fn queue(&self, kind: JobKind) -> Vec<JobId> {
+        let mut queue: Vec<_> = self
+            .waiting
+            .iter()
+            .filter(|waiter| waiter.kind == kind && !waiter.ready.is_canceled())
+            .map(|waiter| waiter.job)
+            .collect();
+        queue.sort_by_key(|job| self.rank(*job));
+        queue
+    }
+
+    fn waiting_kind(&self, job: JobId) -> Option<JobKind> {
+        self.waiting
+            .iter()
+            .find(|waiter| waiter.job == job)
+            .map(|waiter| waiter.kind)
+    }
+}
+
+/// Hands out permits to jobs, shared by everything diffing nodes.
+#[derive(Clone, Default)]
+pub struct Scheduler(Arc<Mutex<State>>);
+
+impl Scheduler {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state stays consistent even if a holder panicked, every update is a single step.
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn limits(&self) -> JobLimits {
+        self.lock().limits
+    }
+
+    pub fn set_limits(&self, limits: JobLimits) {
+        self.lock().limits = limits;
+        self.dispatch();
+    }
+
+    /// Registers a new job with the lowest priority.
+    pub fn job(&self) -> Job {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.order.push(id);
+
+        Job {
+            scheduler: self.clone(),
+            id,
+        }
+    }
+
+    /// The kind of permit `job` is waiting for and its 1-based position in that queue.
+    pub fn position(&self, job: JobId) -> Option<(JobKind, usize)> {
+        let state = self.lock();
+        let kind = state.waiting_kind(job)?;
+        let position = state.queue(kind).iter().position(|id| *id == job)?;
+        Some((kind, position + 1))
+    }
+
+    /// Moves `job` ahead of the job before it in its queue.
+    pub fn move_up(&self, job: JobId) {
+        {
+            let mut state = self.lock();
+            let Some(kind) = state.waiting_kind(job) else {
+                return;
+            };
+            let queue = state.queue(kind);
+            let Some(ahead) = queue
+                .iter()
+                .position(|id| *id == job)
+                .and_then(|position| position.checked_sub(1))
+                .map(|position| queue[position])
+            else {
+                return;
+            };
+
+            state.order.retain(|id| *id != job);
+            let ahead_rank = state.rank(ahead);
+            state.order.insert(ahead_rank, job);
+        }
+        self.dispatch();
+    }
+
+    /// Gives `job` the highest priority of all jobs.
+    pub fn prioritize(&self, job: JobId) {
+        {
+            let mut state = self.lock();
+            state.order.retain(|id| *id != job);
+            state.order.insert(0, job);
+        }
+        self.dispatch();
+    }
+
+    /// Hands out permits to the waiting jobs with the highest priority while there are some left.
+    fn dispatch(&self) {
+        let ready = {
+            let mut state = self.lock();
+            state.waiting.retain(|waiter| !waiter.ready.is_canceled());
+
+            let mut ready = Vec::new();
+            for kind in JobKind::ALL {
+                // A limit of 0 would stall every diff forever.
+                let limit = state.limits.get(kind).max(1);
+                while state.running[kind as usize] < limit {
+                    let Some(next) = state.queue(kind).first().copied() else {
+                        break;
+                    };
+                    let idx = state
+                        .waiting
+                        .iter()
+                        .position(|waiter| waiter.job == next && waiter.kind == kind)
+                        .expect("queued job is waiting");
+                    let waiter = state.waiting.remove(idx);
+                    state.running[kind as usize] += 1;
+                    ready.push((waiter.ready, kind));
+                }
+            }
+            ready
+        };
+
+        // Permits are sent without holding the lock, a permit whose job went away in the
+        // meantime is dropped right here and releases its slot again.
+        for (sender, kind) in ready {
+            let _ = sender.send(Permit {
+                scheduler: self.clone(),
+                kind,
+            });
+        }
+    }
+}
+
+/// A single diff. It loses its place in the queue when dropped.
+pub struct Job {
+    scheduler: Scheduler,
+    id: JobId,
+}
+
+impl Job {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Waits until the job may run work of `kind`.
+    pub async fn acquire(&self, kind: JobKind) -> Permit {
+        let (ready, permit) = oneshot::channel();
+        self.scheduler.lock().waiting.push(Waiter {
+            job: self.id,
+            kind,
+            ready,
+        });
+        self.scheduler.dispatch();
+
+        permit
+            .await
+            .expect("the scheduler outlives the jobs waiting on it")
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.lock();
+        state.order.retain(|id| *id != self.id);
+        state.waiting.retain(|waiter| waiter.job != self.id);
+    }
+}
+
+/// Allows a job to run work of one kind until it's dropped.
+pub struct Permit {
+    scheduler: Scheduler,
+    kind: JobKind,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.lock().running[self.kind as usize] -= 1;
+        self.scheduler.dispatch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::task::{Context, Poll};
+
+    /// Polls `future` once, registering it with the scheduler if it has to wait.
+    fn poll<F: Future>(future: std::pin::Pin<&mut F>) -> Option<F::Output> {
+        let waker = futures::task::noop_waker();
+        match future.poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => Some(output),
+            Poll::Pending => None,
+        }
+    }
+
+    fn scheduler(copy: usize) -> Scheduler {
+        let scheduler = Scheduler::default();
+        scheduler.set_limits(JobLimits {
+            copy,
+            ..JobLimits::default()
+        });
+        scheduler
+    }
+
+    fn queue(scheduler: &Scheduler, jobs: &[&Job]) -> Vec<Option<(JobKind, usize)>> {
+        jobs.iter()
+            .map(|job| scheduler.position(job.id()))
+            .collect()
+    }
+
+    #[test]
+    fn permits_go_to_jobs_in_priority_order() {
+        let scheduler = scheduler(1);
+        let (a, b, c) = (scheduler.job(), scheduler.job(), scheduler.job());
+
+        let permit = poll(pin!(a.acquire(JobKind::Copy))).expect("a slot is free");
+        // c asks first, but b was registered before it.
+        let mut c_copy = pin!(c.acquire(JobKind::Copy));
+        let mut b_copy = pin!(b.acquire(JobKind::Copy));
+        assert!(poll(c_copy.as_mut()).is_none());
+        assert!(poll(b_copy.as_mut()).is_none());
+        assert_eq!(
+            queue(&scheduler, &[&b, &c]),
+            [Some((JobKind::Copy, 1)), Some((JobKind::Copy, 2))]
+        );
+
+        drop(permit);
+        let _b_permit = poll(b_copy.as_mut()).expect("b is next");
+        assert!(poll(c_copy.as_mut()).is_none());
+        assert_eq!(scheduler.position(c.id()), Some((JobKind::Copy, 1)));
+    }
+
+    #[test]
+    fn kinds_have_separate_limits() {
+        let scheduler = scheduler(1);
+        let (a, b) = (scheduler.job(), scheduler.job());
+
+        let _copy = poll(pin!(a.acquire(JobKind::Copy))).expect("a slot is free");
+        assert!(poll(pin!(b.acquire(JobKind::Build))).is_some());
+    }
+
+    #[test]
+    fn move_up_passes_the_job_ahead() {
+        let scheduler = scheduler(1);
+        let (a, b, c, d) = (
+            scheduler.job(),
+            scheduler.job(),
+            scheduler.job(),
+            scheduler.job(),
+        );
+
+        let _permit = poll(pin!(a.acquire(JobKind::Copy))).expect("a slot is free");
+        let mut b_copy = pin!(b.acquire(JobKind::Copy));
+        let mut c_copy = pin!(c.acquire(JobKind::Copy));
+        let mut d_copy = pin!(d.acquire(JobKind::Copy));
+        assert!(poll(b_copy.as_mut()).is_none());
+        assert!(poll(c_copy.as_mut()).is_none());
+        assert!(poll(d_copy.as_mut()).is_none());
+
+        scheduler.move_up(d.id());
+        assert_eq!(
+            queue(&scheduler, &[&b, &c, &d]),
+            [
+                Some((JobKind::Copy, 1)),
+                Some((JobKind::Copy, 3)),
+                Some((JobKind::Copy, 2))
+            ]
+        );
+
+        scheduler.move_up(d.id());
+        scheduler.move_up(d.id());
+        assert_eq!(
+            queue(&scheduler, &[&b, &c, &d]),
+            [
+                Some((JobKind::Copy, 2)),
+                Some((JobKind::Copy, 3)),
+                Some((JobKind::Copy, 1))
+            ]
+        );
+        // The running job isn't queued, moving it does nothing.
+        scheduler.move_up(a.id());
+        assert_eq!(scheduler.position(a.id()), None);
+    }
+
+    #[test]
+    fn prioritized_job_gets_the_next_permit() {
+        let scheduler = scheduler(1);
+        let (a, b, c) = (scheduler.job(), scheduler.job(), scheduler.job());
+
+        let permit = poll(pin!(a.acquire(JobKind::Copy))).expect("a slot is free");
+        let mut b_copy = pin!(b.acquire(JobKind::Copy));
+        let mut c_copy = pin!(c.acquire(JobKind::Copy));
+        assert!(poll(b_copy.as_mut()).is_none());
+        assert!(poll(c_copy.as_mut()).is_none());
+
+        scheduler.prioritize(c.id());
+        assert_eq!(scheduler.position(c.id()), Some((JobKind::Copy, 1)));
+
+        drop(permit);
+        let _c_permit = poll(c_copy.as_mut()).expect("c is next");
+        assert!(poll(b_copy.as_mut()).is_none());
+    }
+
+    #[test]
+    fn zero_limit_still_runs_one_job() {
+        let scheduler = scheduler(0);
+        let (a, b) = (scheduler.job(), scheduler.job());
+
+        let _permit = poll(pin!(a.acquire(JobKind::Copy))).expect("a limit of 0 allows one job");
+        assert!(poll(pin!(b.acquire(JobKind::Copy))).is_none());
+    }
+
+    #[test]
+    fn raising_the_limit_starts_waiting_jobs() {
+        let scheduler = scheduler(1);
+        let (a, b) = (scheduler.job(), scheduler.job());
+
+        let _permit = poll(pin!(a.acquire(JobKind::Copy))).expect("a slot is free");
+        let mut b_copy = pin!(b.acquire(JobKind::Copy));
+        assert!(poll(b_copy.as_mut()).is_none());
+
+        scheduler.set_limits(JobLimits {
+            copy: 2,
+            ..scheduler.limits()
+        });
+        assert!(poll(b_copy.as_mut()).is_some());
+    }
+
+    #[test]
+    fn dropped_jobs_leave_the_queue() {
+        let scheduler = scheduler(1);
+        let (a, b, c) = (scheduler.job(), scheduler.job(), scheduler.job());
+
+        let permit = poll(pin!(a.acquire(JobKind::Copy))).expect("a slot is free");
+        let mut c_copy = pin!(c.acquire(JobKind::Copy));
+        assert!(poll(c_copy.as_mut()).is_none());
+        {
+            let mut b_copy = pin!(b.acquire(JobKind::Copy));
+            assert!(poll(b_copy.as_mut()).is_none());
+            assert_eq!(scheduler.position(c.id()), Some((JobKind::Copy, 2)));
+        }
+        drop(b);
+        assert_eq!(scheduler.position(c.id()), Some((JobKind::Copy, 1)));
+
+        drop(permit);
+        assert!(poll(c_copy.as_mut()).is_some());
+    }
+}
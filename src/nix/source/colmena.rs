@@ -0,0 +1,56 @@
+//! Colmena hives, either a `hive.nix` evaluated by colmena or the `colmenaHive` output of a
+//! flake, which plain nix can evaluate.
+//!
+//! Nodes are reached at their `deployment.targetHost`, `targetPort` and `targetUser`.
+
+use crate::nix::ClusterConfig;
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use crate::nix::source::flake;
+use duct::cmd;
+use std::path::Path;
+
+pub const HIVE_FILE: &str = "hive.nix";
+pub const DEFAULT_ATTR_ROOT: &str = "colmenaHive.nodes";
+
+pub const NODE_FUNCTION: &str = "node: let deployment = node.config.deployment; in { \
+    host = deployment.targetHost; hostSource = \"target-host\"; port = deployment.targetPort; \
+    user = deployment.targetUser; system = node.config.system.build.toplevel; }";
+
+fn is_hive_file(config: &ClusterConfig) -> bool {
+    config.cluster_file().ends_with(HIVE_FILE)
+}
+
+pub async fn eval_nodes(
+    config: &ClusterConfig,
+    apply: &str,
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    if !is_hive_file(config) {
+        return flake::eval_nodes(config, apply, log).await;
+    }
+
+    let cluster_dir = config.cluster_dir()?;
+    let hive = config.cluster_file();
+    let expr = format!("{{ nodes, ... }}: ({apply}) nodes");
+    let hive_path = hive.to_string_lossy();
+    let args = config.colmena_args(&["eval", "--config", &hive_path, "-E", &expr]);
+    let output = log
+        .run(
+            format!("colmena {}", args.join(" ")),
+            cmd("colmena", &args).dir(cluster_dir),
+        )
+        .await
+        .map_err(|err| NixError::spawn("colmena", err))?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(NixError::eval(&hive_path, output.stderr))
+    }
+}
+
+/// Only hives in a flake can be evaluated without colmena.
+pub fn nodes_expression(config: &ClusterConfig, cluster_dir: &Path) -> Option<String> {
+    (!is_hive_file(config)).then(|| flake::nodes_expression(config, cluster_dir))
+}
@@ -0,0 +1,19 @@
+//! deploy-rs nodes in the `deploy.nodes` output of the cluster's flake.
+//!
+//! Nodes are reached at their `hostname` as their `sshUser`, on the port passed with `-p` in
+//! their `sshOpts` if any. Their system is the `system` profile deploy-rs activates, which is
+//! the `activatable-…` wrapper around the toplevel. The node's `system` profile links to that
+//! same wrapper, so both sides of a diff are wrappers.
+
+pub const DEFAULT_ATTR_ROOT: &str = "deploy.nodes";
+
+// Profile settings override the node's, like deploy-rs does.
+pub const NODE_FUNCTION: &str = "node: let \
+    profile = node.profiles.system; \
+    sshOpts = profile.sshOpts or node.sshOpts or [ ]; \
+    port = (builtins.foldl' (acc: opt: \
+        if acc.next then { next = false; port = builtins.fromJSON opt; } \
+        else if opt == \"-p\" then acc // { next = true; } \
+        else acc) { next = false; port = null; } sshOpts).port; \
+    in { host = node.hostname; hostSource = \"deploy-rs\"; inherit port; \
+    user = profile.sshUser or node.sshUser or null; system = profile.path; }";
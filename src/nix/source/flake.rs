@@ -0,0 +1,66 @@
+//! NixOS configurations in an output of the cluster's flake, `nixosConfigurations` by default.
+//!
+//! The flake is the cluster directory unless the cluster sets a flake reference.
+
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use crate::nix::{ClusterConfig, nix_eval, nix_string};
+use std::path::Path;
+
+pub const DEFAULT_ATTR_ROOT: &str = "nixosConfigurations";
+
+/// Nodes are plain NixOS configurations. Their address is the first of `ip_attr`,
+/// `deployment.targetHost`, the first IPv4 address of their interfaces and
+/// `networking.fqdn` that's set.
+pub fn node_function(ip_attr: &str) -> String {
+    format!(
+        "node: let \
+         config = node.config; \
+         try = value: let result = builtins.tryEval value; \
+         in if result.success then result.value else null; \
+         interfaceAddresses = builtins.concatMap (interface: interface.ipv4.addresses or [ ]) \
+         (builtins.attrValues (config.networking.interfaces or {{ }})); \
+         candidates = [ \
+         {{ source = \"ip-attr\"; host = try (node.{ip_attr} or null); }} \
+         {{ source = \"target-host\"; host = try (config.deployment.targetHost or null); }} \
+         {{ source = \"interfaces\"; host = if interfaceAddresses == [ ] then null \
+         else (builtins.head interfaceAddresses).address; }} \
+         {{ source = \"fqdn\"; host = try (config.networking.fqdn or null); }} \
+         ]; \
+         found = builtins.filter (candidate: candidate.host != null && candidate.host != \"\") \
+         candidates; \
+         address = if found == [ ] then {{ source = null; host = null; }} else builtins.head found; \
+         in {{ inherit (address) host; hostSource = address.source; port = null; user = null; \
+         system = config.system.build.toplevel; }}"
+    )
+}
+
+pub async fn eval_nodes(
+    config: &ClusterConfig,
+    apply: &str,
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    let flake_ref = match config.flake_ref.trim() {
+        "" => ".",
+        flake_ref => flake_ref,
+    };
+    let attr_root = format!("{flake_ref}#{}", config.attr_root());
+    nix_eval(config, &attr_root, &["--apply", apply], log).await
+}
+
+/// getFlake doesn't resolve relative references, those only work with [`eval_nodes`].
+pub fn nodes_expression(config: &ClusterConfig, cluster_dir: &Path) -> String {
+    let flake = match config.flake_ref.trim() {
+        "" => nix_string(&cluster_dir.to_string_lossy()),
+        flake_ref => nix_string(flake_ref),
+    };
+    format!("(builtins.getFlake {flake}).{}", config.attr_root())
+}
+
+/// The revision pinned by the flake reference's `rev` parameter.
+pub fn pinned_rev(flake_ref: &str) -> Option<&str> {
+    let (_, params) = flake_ref.split_once('?')?;
+    params
+        .split('&')
+        .find_map(|param| param.strip_prefix("rev="))
+}
@@ -0,0 +1,124 @@
+//! A static TOML or JSON inventory of nodes, for machines whose address isn't in their
+//! configuration.
+//!
+//! ```toml
+//! [nodes.web1]
+//! address = "10.0.0.1"
+//! port = 2222
+//! user = "root"
+//! jump_host = "admin@bastion:22"
+//! attribute = "nixosConfigurations.web"
+//! ```
+//!
+//! Only the address is required. A node's system is the NixOS configuration at its
+//! attribute in the cluster's flake, `<nodes attribute>.<node>` by default. The flake is the
+//! directory of the inventory unless the cluster sets a flake reference. It's fetched with
+//! getFlake, so overridden inputs don't apply to it.
+
+use crate::nix::error::NixError;
+use crate::nix::process::{CommandLog, unblock};
+use crate::nix::{ClusterConfig, nix_eval_expr, nix_string};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_ATTR_ROOT: &str = "nixosConfigurations";
+
+// Inventory nodes are evaluated to the shape every source maps its nodes to.
+pub const NODE_FUNCTION: &str = "node: node";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Inventory {
+    #[serde(default)]
+    nodes: BTreeMap<String, InventoryNode>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InventoryNode {
+    address: String,
+    port: Option<u16>,
+    user: Option<String>,
+    /// Host to jump through, as `[user@]host[:port]`.
+    jump_host: Option<String>,
+    /// Attribute path of the node's NixOS configuration in the flake.
+    attribute: Option<String>,
+}
+
+/// Whether `cluster_file` looks like an inventory.
+pub fn is_inventory_file(cluster_file: &Path) -> bool {
+    cluster_file
+        .extension()
+        .is_some_and(|extension| extension == "toml" || extension == "json")
+}
+
+fn read_inventory(path: &Path) -> Result<Inventory, NixError> {
+    let invalid = |reason: String| NixError::ClusterPath {
+        path: path.to_path_buf(),
+        reason,
+    };
+
+    let raw = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        serde_json::from_str(&raw).map_err(|err| invalid(format!("Invalid inventory: {err}")))
+    } else {
+        toml::from_str(&raw).map_err(|err| invalid(format!("Invalid inventory: {err}")))
+    }
+}
+
+/// Quotes `value` as a nix string, or null.
+fn nix_optional(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_owned(), nix_string)
+}
+
+/// An attribute set of all nodes of the inventory in
+/// `{ host, hostSource, port, user, jump, system }` form.
+pub async fn nodes_expression(
+    config: &ClusterConfig,
+    cluster_dir: &Path,
+) -> Result<String, NixError> {
+    let path = config.cluster_file();
+    let inventory = unblock(move || read_inventory(&path)).await?;
+
+    let flake = match config.flake_ref.trim() {
+        "" => nix_string(&cluster_dir.to_string_lossy()),
+        flake_ref => nix_string(flake_ref),
+    };
+    let mut expr = format!("let flake = builtins.getFlake {flake}; in {{");
+    for (name, node) in &inventory.nodes {
+        let attribute = node
+            .attribute
+            .clone()
+            .unwrap_or_else(|| format!("{}.{}", config.attr_root(), nix_string(name)));
+        let _ = write!(
+            expr,
+            " {} = {{ host = {}; hostSource = \"inventory\"; port = {}; user = {}; jump = {}; \
+             system = flake.{attribute}.config.system.build.toplevel; }};",
+            nix_string(name),
+            nix_string(&node.address),
+            node.port
+                .map_or_else(|| "null".to_owned(), |port| port.to_string()),
+            nix_optional(node.user.as_deref()),
+            nix_optional(node.jump_host.as_deref()),
+        );
+    }
+    expr.push_str(" }");
+    Ok(expr)
+}
+
+pub async fn eval_nodes(
+    config: &ClusterConfig,
+    apply: &str,
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    let cluster_dir = config.cluster_dir()?;
+    let nodes = nodes_expression(config, &cluster_dir).await?;
+    let expr = format!("({apply}) ({nodes})");
+    nix_eval_expr(config, &expr, &config.cluster_file().to_string_lossy(), log).await
+}
@@ -0,0 +1,351 @@
+//! Where the nodes of a cluster are defined and how to evaluate them.
+//!
+//! Every layout of a cluster has an adapter that can evaluate a nix function applied to the
+//! attribute set of its nodes, and that knows how to get from one of its nodes to the
+//! [`Target`] to connect to and the system to build. Everything else, listing the nodes and
+//! evaluating one or many of them, works the same for every source.
+
+use crate::nix::batch::BatchNode;
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use crate::nix::{ClusterConfig, nix_string};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write};
+use std::path::Path;
+
+pub mod colmena;
+pub mod deploy_rs;
+pub mod flake;
+pub mod inventory;
+pub mod nixos_dir;
+
+/// The layout of a cluster.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceKind {
+    /// Picked by the cluster file, see [`SourceKind::resolve`].
+    #[default]
+    Auto,
+    /// NixOS configurations in a flake output.
+    Flake,
+    /// A colmena hive, either a `hive.nix` or the `colmenaHive` output of a flake.
+    Colmena,
+    /// deploy-rs nodes in a flake output.
+    DeployRs,
+    /// A directory of non-flake NixOS configurations.
+    NixosDir,
+    /// A TOML or JSON file listing the nodes and how to reach them.
+    Inventory,
+}
+
+impl SourceKind {
+    pub const ALL: [SourceKind; 6] = [
+        SourceKind::Auto,
+        SourceKind::Flake,
+        SourceKind::Colmena,
+        SourceKind::DeployRs,
+        SourceKind::NixosDir,
+        SourceKind::Inventory,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SourceKind::Auto => "Auto",
+            SourceKind::Flake => "Flake",
+            SourceKind::Colmena => "Colmena",
+            SourceKind::DeployRs => "deploy-rs",
+            SourceKind::NixosDir => "NixOS directory",
+            SourceKind::Inventory => "Inventory",
+        }
+    }
+
+    /// The source of the cluster in `cluster_file`, with [`SourceKind::Auto`] resolved.
+    pub fn resolve(self, cluster_file: &Path) -> SourceKind {
+        match self {
+            SourceKind::Auto if cluster_file.ends_with(colmena::HIVE_FILE) => SourceKind::Colmena,
+            SourceKind::Auto if cluster_file.is_dir() => SourceKind::NixosDir,
+            SourceKind::Auto if inventory::is_inventory_file(cluster_file) => SourceKind::Inventory,
+            SourceKind::Auto => SourceKind::Flake,
+            kind => kind,
+        }
+    }
+
+    /// Attribute containing the nodes when the cluster doesn't configure one.
+    pub fn default_attr_root(self) -> &'static str {
+        match self {
+            SourceKind::Auto | SourceKind::Flake => flake::DEFAULT_ATTR_ROOT,
+            SourceKind::Colmena => colmena::DEFAULT_ATTR_ROOT,
+            SourceKind::DeployRs => deploy_rs::DEFAULT_ATTR_ROOT,
+            SourceKind::NixosDir => nixos_dir::DEFAULT_ATTR_ROOT,
+            SourceKind::Inventory => inventory::DEFAULT_ATTR_ROOT,
+        }
+    }
+
+    /// Where the source's nodes are reached, if not at the IP attribute.
+    pub fn target_description(self) -> Option<&'static str> {
+        match self {
+            SourceKind::Auto | SourceKind::Flake | SourceKind::NixosDir => None,
+            SourceKind::Colmena => Some("Colmena nodes are reached at their deployment.targetHost"),
+            SourceKind::DeployRs => Some("deploy-rs nodes are reached at their hostname"),
+            SourceKind::Inventory => Some("Inventory nodes are reached at their address"),
+        }
+    }
+}
+
+impl Display for SourceKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// What the address of a node was taken from, in the order they're tried for nodes whose
+/// source doesn't define their address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressSource {
+    /// The configured IP address attribute.
+    IpAttr,
+    /// `deployment.targetHost`, set by colmena.
+    TargetHost,
+    /// The first IPv4 address of `networking.interfaces`.
+    Interfaces,
+    /// `networking.fqdn`.
+    Fqdn,
+    /// The `HostName` ~/.ssh/config has for the node's name.
+    SshConfig,
+    /// The `hostname` of a deploy-rs node.
+    DeployRs,
+    /// The address in the inventory.
+    Inventory,
+}
+
+impl AddressSource {
+    pub fn label(self) -> &'static str {
+        match self {
+            AddressSource::IpAttr => "IP address attribute",
+            AddressSource::TargetHost => "deployment.targetHost",
+            AddressSource::Interfaces => "networking.interfaces",
+            AddressSource::Fqdn => "networking.fqdn",
+            AddressSource::SshConfig => "ssh config HostName",
+            AddressSource::DeployRs => "deploy-rs hostname",
+            AddressSource::Inventory => "inventory",
+        }
+    }
+}
+
+/// Where to reach a node, as far as its source knows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    /// IP address or host name.
+    pub host: String,
+    pub source: AddressSource,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// Host to jump through, as `[user@]host[:port]`.
+    pub jump: Option<String>,
+}
+
+/// What evaluating a node yields.
+#[derive(Debug, Clone)]
+pub struct EvaluatedNode {
+    pub target: Target,
+    /// Derivation of the node's system.
+    pub drv_path: String,
+}
+
+/// A node as [`summary`] evaluates it.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawNode {
+    host: Option<String>,
+    host_source: Option<AddressSource>,
+    port: Option<u16>,
+    user: Option<String>,
+    jump: Option<String>,
+    drv_path: String,
+}
+
+impl RawNode {
+    fn into_evaluated(self, node_name: &str) -> EvaluatedNode {
+        let (host, source) = resolve_address(node_name, self.host, self.host_source);
+        EvaluatedNode {
+            target: Target {
+                host,
+                source,
+                port: self.port,
+                user: self.user,
+                jump: self.jump,
+            },
+            drv_path: self.drv_path,
+        }
+    }
+}
+
+/// The address of `node_name` from the `host` its evaluation found and its `source`.
+///
+/// Without one the node is reached at its name, as long as ~/.ssh/config gives it a
+/// `HostName`, which is only known once connecting.
+pub fn resolve_address(
+    node_name: &str,
+    host: Option<String>,
+    source: Option<AddressSource>,
+) -> (String, AddressSource) {
+    match host.filter(|host| !host.is_empty()) {
+        Some(host) => (host, source.unwrap_or(AddressSource::IpAttr)),
+        None => (node_name.to_owned(), AddressSource::SshConfig),
+    }
+}
+
+impl ClusterConfig {
+    /// The cluster's source, with [`SourceKind::Auto`] resolved.
+    pub fn source(&self) -> SourceKind {
+        self.source.resolve(&self.cluster_file())
+    }
+
+    /// Attribute path of the nodes, the source's default unless configured.
+    pub fn attr_root(&self) -> &str {
+        match self.attr_root.trim() {
+            "" => self.source().default_attr_root(),
+            attr_root => attr_root,
+        }
+    }
+}
+
+/// A nix function from a node of the cluster to its target and system,
+/// `{ host, hostSource, port, user, system }`, and optionally the `jump` host to reach it
+/// through. `host` is null if the node's address wasn't found.
+///
+/// `ip_attr` is the attribute path of the node's address, for sources that don't define one.
+fn node_function(config: &ClusterConfig, ip_attr: &str) -> String {
+    match config.source() {
+        SourceKind::Auto | SourceKind::Flake | SourceKind::NixosDir => {
+            flake::node_function(ip_attr)
+        }
+        SourceKind::Colmena => colmena::NODE_FUNCTION.to_owned(),
+        SourceKind::DeployRs => deploy_rs::NODE_FUNCTION.to_owned(),
+        SourceKind::Inventory => inventory::NODE_FUNCTION.to_owned(),
+    }
+}
+
+/// Evaluates the function `apply` applied to the attribute set of all nodes, returning the
+/// JSON result.
+async fn eval_nodes(
+    config: &ClusterConfig,
+    apply: &str,
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    match config.source() {
+        SourceKind::Auto | SourceKind::Flake | SourceKind::DeployRs => {
+            flake::eval_nodes(config, apply, log).await
+        }
+        SourceKind::Colmena => colmena::eval_nodes(config, apply, log).await,
+        SourceKind::NixosDir => nixos_dir::eval_nodes(config, apply, log).await,
+        SourceKind::Inventory => inventory::eval_nodes(config, apply, log).await,
+    }
+}
+
+/// A plain nix expression of the attribute set of all nodes, for evaluators other than the
+/// source's own. None if the source can only be evaluated by its own tooling.
+///
+/// Flakes are fetched with getFlake, which can't override their inputs, so flakes with
+/// overridden inputs can't be evaluated this way either.
+pub async fn nodes_expression(config: &ClusterConfig) -> Result<Option<String>, NixError> {
+    let cluster_dir = config.cluster_dir()?;
+    let source = config.source();
+    let expression = match source {
+        SourceKind::Auto | SourceKind::Flake | SourceKind::DeployRs => {
+            Some(flake::nodes_expression(config, &cluster_dir))
+        }
+        SourceKind::Colmena => colmena::nodes_expression(config, &cluster_dir),
+        SourceKind::NixosDir => Some(nixos_dir::nodes_expression(config, &cluster_dir)),
+        SourceKind::Inventory => Some(inventory::nodes_expression(config, &cluster_dir).await?),
+    };
+    Ok(expression.filter(|_| {
+        // Falling back doesn't help either, NixOS directories have no inputs and
+        // inventories always fetch their flake with getFlake.
+        matches!(source, SourceKind::NixosDir | SourceKind::Inventory)
+            || config.nix_flags.override_inputs.is_empty()
+    }))
+}
+
+/// A nix expression of `node_name`'s `{ host, hostSource, port, user, system }` and optional
+/// `jump`, given `nodes`.
+pub fn node_expression(config: &ClusterConfig, node_name: &str, ip_attr: &str) -> String {
+    format!(
+        "({}) nodes.{}",
+        node_function(config, ip_attr),
+        nix_string(node_name)
+    )
+}
+
+/// A nix expression of what [`RawNode`] reads from the node `node_expression` evaluates to.
+fn summary(node_expression: &str) -> String {
+    format!(
+        "let node = {node_expression}; in {{ inherit (node) host hostSource port user; \
+         jump = node.jump or null; drvPath = node.system.drvPath; }}"
+    )
+}
+
+/// Names of all nodes of the cluster.
+pub async fn list_nodes(config: &ClusterConfig) -> Result<Vec<String>, NixError> {
+    let output = eval_nodes(config, "builtins.attrNames", &CommandLog::disabled()).await?;
+    serde_json::from_str(&output).map_err(|_| NixError::InvalidOutput {
+        expected: "a list of node names".to_owned(),
+        output,
+    })
+}
+
+/// Evaluates where to reach `node_name` and the derivation of its system.
+pub async fn evaluate_node(
+    config: &ClusterConfig,
+    node_name: &str,
+    ip_attr: &str,
+    log: &CommandLog,
+) -> Result<EvaluatedNode, NixError> {
+    let apply = format!(
+        "nodes: {}",
+        summary(&node_expression(config, node_name, ip_attr))
+    );
+    let output = eval_nodes(config, &apply, log).await?;
+
+    let node: RawNode = serde_json::from_str(&output).map_err(|_| NixError::InvalidOutput {
+        expected: "the address and system derivation of the node".to_owned(),
+        output: output.clone(),
+    })?;
+    Ok(node.into_evaluated(node_name))
+}
+
+/// Evaluates where to reach all `nodes` and the derivations of their systems at once.
+pub async fn evaluate_nodes(
+    config: &ClusterConfig,
+    nodes: &[BatchNode],
+    log: &CommandLog,
+) -> Result<BTreeMap<String, EvaluatedNode>, NixError> {
+    let mut apply = String::from("nodes: {");
+    for node in nodes {
+        let _ = write!(
+            apply,
+            " {} = {};",
+            nix_string(&node.name),
+            summary(&node_expression(config, &node.name, &node.ip_attr))
+        );
+    }
+    apply.push_str(" }");
+    let output = eval_nodes(config, &apply, log).await?;
+
+    let invalid_output = || NixError::InvalidOutput {
+        expected: "the address and system derivation of every node".to_owned(),
+        output: output.clone(),
+    };
+    let raw: BTreeMap<String, RawNode> =
+        serde_json::from_str(&output).map_err(|_| invalid_output())?;
+
+    Ok(raw
+        .into_iter()
+        .map(|(name, node)| {
+            let node = node.into_evaluated(&name);
+            (name, node)
+        })
+        .collect())
+}
@@ -0,0 +1,38 @@
+//! A directory of plain, non-flake NixOS configurations, one `<node>/configuration.nix` per
+//! node, evaluated against the `nixpkgs` of the NIX_PATH.
+//!
+//! The nodes attribute is the directory of the nodes relative to the cluster directory.
+
+use crate::nix::error::NixError;
+use crate::nix::process::CommandLog;
+use crate::nix::{ClusterConfig, nix_eval_expr, nix_string};
+use std::path::Path;
+
+pub const DEFAULT_ATTR_ROOT: &str = ".";
+
+/// An attribute set of the NixOS systems of all nodes in the directory.
+pub fn nodes_expression(config: &ClusterConfig, cluster_dir: &Path) -> String {
+    let dir = nix_string(&cluster_dir.join(config.attr_root()).to_string_lossy());
+    format!(
+        "let \
+         dir = /. + {dir}; \
+         entries = builtins.readDir dir; \
+         isNode = name: entries.${{name}} == \"directory\" \
+         && builtins.pathExists (dir + \"/${{name}}/configuration.nix\"); \
+         names = builtins.filter isNode (builtins.attrNames entries); \
+         system = name: import <nixpkgs/nixos> {{ configuration = dir + \"/${{name}}/configuration.nix\"; }}; \
+         in if names == [ ] \
+         then throw \"No flake.nix, hive.nix or <node>/configuration.nix found in ${{toString dir}}\" \
+         else builtins.listToAttrs (map (name: {{ inherit name; value = system name; }}) names)"
+    )
+}
+
+pub async fn eval_nodes(
+    config: &ClusterConfig,
+    apply: &str,
+    log: &CommandLog,
+) -> Result<String, NixError> {
+    let cluster_dir = config.cluster_dir()?;
+    let expr = format!("({apply}) ({})", nodes_expression(config, &cluster_dir));
+    nix_eval_expr(config, &expr, &cluster_dir.to_string_lossy(), log).await
+}
@@ -1,3 +1,38 @@
+use crate::settings::Settings;
+use iced::{Element, Task};
+use iced_futures::Subscription;
+use std::fmt::Debug;
+
 pub mod ping;
 pub mod nix_diff;
-pub mod nix_cluster;
\ No newline at end of file
+pub mod nix_cluster;
+pub mod nix_workspace;
+pub mod registry;
+
+/// A tool page shown in the sidebar of CheckIT.
+///
+/// Pages are registered once in the [`registry::PageRegistry`] and stay alive while hidden,
+/// so their subscriptions and running tasks continue in the background.
+pub trait Page {
+    type Message: Debug + Clone + Send + 'static;
+
+    fn title(&self) -> &str;
+
+    fn icon(&self) -> char;
+
+    fn update(&mut self, message: Self::Message) -> Task<Self::Message>;
+
+    fn view(&self) -> Element<'_, Self::Message>;
+
+    fn subscription(&self) -> Subscription<Self::Message> {
+        Subscription::none()
+    }
+
+    /// Restores the page's state from the persisted settings on launch.
+    fn restore(&mut self, _settings: &Settings) -> Task<Self::Message> {
+        Task::none()
+    }
+
+    /// Writes the page's state into the settings before they're persisted.
+    fn persist(&self, _settings: &mut Settings) {}
+}
@@ -1,61 +1,180 @@
-use crate::pages::nix_diff::{NixNodeDiffView, fetch_cluster_nodes};
-use iced::widget::{button, column, container, row, text, text_input};
-use iced::{Color, Element, Length, Padding, Task};
+use crate::nix::batch::{BatchEvent, BatchNode, PreparedNode, prepare_nodes};
+use crate::nix::cache::{self, DiffKey};
+use crate::nix::diff::{DiffStage, NodeStatus};
+use crate::nix::error::DiffError;
+use crate::nix::process::unblock;
+use crate::nix::progress::DiffProgress;
+use crate::nix::scheduler::Scheduler;
+use crate::nix::source::{self, SourceKind, Target};
+use crate::nix::{ClusterConfig, EvalJobs};
+use crate::pages::nix_diff::{NixNodeDiffView, error_card, log_pane, push_log_line};
+use crate::settings::{ClusterLibrary, ClusterProfile, ClusterState};
+use crate::utils::format;
+use iced::widget::{button, checkbox, column, container, pick_list, row, text, text_input};
+use iced::{Alignment, Color, Element, Length, Padding, Task, task};
 use iced_aw::selection_list;
-use log::error;
+use log::{debug, error};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter, Write};
 use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub enum Message {
+    SourceChanged(SourceKind),
+    FlakeRefChanged(String),
     IpAttrChanged(String),
+    AttrRootChanged(String),
+    ImpureToggled(bool),
+    AcceptFlakeConfigToggled(bool),
+    OverrideInputsChanged(String),
+    NixOptionsChanged(String),
+    NixArgsChanged(String),
+    SshUserChanged(String),
+    SshPortChanged(String),
+    EvalJobsToggled(bool),
+    EvalWorkersChanged(String),
+    EvalMemoryChanged(String),
     ClusterPathChanged(String),
     PickClusterDir,
+    OpenRecent(RecentCluster),
+    ProfileNameChanged(String),
+    LoadProfile(String),
+    SaveProfile,
+    DeleteProfile,
     StartUpdateClusterInfo,
     UpdateClusterInfo(Option<Vec<String>>),
-    NodeNameChange(usize, String),
+    NodeNameChange(usize, NodeEntry),
     Error(String),
-    NodeDiffMessage(usize, super::nix_diff::Message),
+    NodeDiff(usize, super::nix_diff::Message),
     DiffAll,
+    /// Progress of evaluating and building all nodes at once for "Diff All".
+    Batch(BatchEvent),
+    BatchFailed(DiffError),
+    ToggleBatchLog,
+    ToggleBatchErrorDetails,
+    CancelAll,
+    /// Moves a node's diff one place up in its queue.
+    MoveUp(usize),
+    /// Moves a node's diff to the front of every queue.
+    Prioritize(usize),
 }
 
+/// Entry of the recent clusters menu.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentCluster(PathBuf);
+
+impl Display for RecentCluster {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+/// Entry of the node list, a node with the outcome of its last diff.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeEntry {
+    name: String,
+    status: Option<NodeStatus>,
+}
+
+impl Display for NodeEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{} ({})", self.name, status.label()),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct NixClusterView {
-    ip_attr: String,
-    cluster_path: PathBuf,
+    config: ClusterConfig,
+    /// Raw contents of the override inputs input, parsed into `config.nix_flags` on change.
+    override_inputs_input: String,
+    /// Raw contents of the nix options input, parsed into `config.nix_flags` on change.
+    nix_options_input: String,
+    /// Raw contents of the nix arguments input, split into `config.nix_args` on change.
+    nix_args_input: String,
+    ssh_port_input: String,
+    eval_workers_input: String,
+    eval_memory_input: String,
+    profile_name: String,
     all_cluster_nodes: Vec<String>,
+    /// The nodes as listed, kept in sync with the outcome of their diffs.
+    node_entries: Vec<NodeEntry>,
     node_diff_views: Vec<NixNodeDiffView>,
     loading_cluster: bool,
     error: Option<String>,
     current_node: Option<usize>,
-}
-
-impl Default for NixClusterView {
-    fn default() -> Self {
-        Self {
-            ip_attr: "config.base.primaryIP.address".to_owned(),
-            cluster_path: PathBuf::new(),
-            all_cluster_nodes: Vec::new(),
-            node_diff_views: Vec::new(),
-            loading_cluster: false,
-            error: None,
-            current_node: None,
-        }
-    }
+    /// Per node IP attributes restored from the settings, applied once the nodes are loaded.
+    restored_ip_attrs: BTreeMap<String, String>,
+    restored_node: Option<String>,
+    restored_last_diffs: BTreeMap<String, DiffKey>,
+    /// Aborts evaluating and building all nodes for "Diff All".
+    batch_task: Option<task::Handle>,
+    batch_progress: Option<DiffProgress>,
+    /// Stderr of the commands run for the last "Diff All" to evaluate and build all nodes.
+    batch_log: VecDeque<String>,
+    batch_log_expanded: bool,
+    /// Why evaluating and building all nodes at once failed the last time, after which they
+    /// were diffed one by one.
+    batch_error: Option<DiffError>,
+    batch_error_expanded: bool,
+    /// Nodes of the last "Diff All" as they finished evaluating, with where they're reached or
+    /// why they failed.
+    evaluations: BTreeMap<String, Result<Target, String>>,
+    batch_size: usize,
 }
 
 impl NixClusterView {
-    pub fn update(&mut self, message: Message) -> Task<Message> {
+    pub fn update(
+        &mut self,
+        message: Message,
+        library: &mut ClusterLibrary,
+        scheduler: &Scheduler,
+    ) -> Task<Message> {
         match message {
+            // Reloading replaces the node views, so it waits until their diffs are done.
+            Message::PickClusterDir
+            | Message::OpenRecent(_)
+            | Message::LoadProfile(_)
+            | Message::StartUpdateClusterInfo
+                if self.is_diffing() => {}
             Message::PickClusterDir => {
                 if let Some(cluster_dir) = rfd::FileDialog::new()
-                    .set_directory(&self.cluster_path)
+                    .set_directory(&self.config.cluster_path)
                     .pick_file()
                 {
-                    self.cluster_path = cluster_dir;
+                    self.config.cluster_path = cluster_dir;
                     return self.start_cluster_info_update();
                 };
             }
             Message::ClusterPathChanged(path) => {
-                self.cluster_path = PathBuf::from(path);
+                self.config.cluster_path = PathBuf::from(path);
+            }
+            Message::OpenRecent(RecentCluster(path)) => {
+                self.config.cluster_path = path;
+                return self.start_cluster_info_update();
+            }
+            Message::ProfileNameChanged(name) => self.profile_name = name,
+            Message::LoadProfile(name) => {
+                if let Some(profile) = library.profiles.iter().find(|p| p.name == name) {
+                    self.set_config(profile.config.clone());
+                    self.profile_name = name;
+                    return self.start_cluster_info_update();
+                }
+            }
+            Message::SaveProfile => {
+                let profile = ClusterProfile {
+                    name: self.profile_name.trim().to_owned(),
+                    config: self.config.clone(),
+                };
+                match library.profiles.iter_mut().find(|p| p.name == profile.name) {
+                    Some(existing) => *existing = profile,
+                    None => library.profiles.push(profile),
+                }
+            }
+            Message::DeleteProfile => {
+                library.profiles.retain(|p| p.name != self.profile_name);
             }
             Message::StartUpdateClusterInfo => {
                 return self.start_cluster_info_update();
@@ -64,78 +183,333 @@ impl NixClusterView {
                 self.error = None;
                 self.loading_cluster = false;
                 if let Some(nodes) = nodes {
+                    library.remember_recent_cluster(self.config.cluster_path.clone());
                     self.all_cluster_nodes = nodes;
                     self.node_diff_views = self
                         .all_cluster_nodes
                         .iter()
                         .map(|node| {
-                            NixNodeDiffView::new(
-                                self.cluster_path.clone(),
-                                self.ip_attr.clone(),
-                                node.clone(),
-                            )
+                            let ip_attr = self.restored_ip_attrs.remove(node);
+                            NixNodeDiffView::new(node.clone(), ip_attr)
                         })
                         .collect();
+                    self.restored_ip_attrs.clear();
+                    self.refresh_node_entries();
+
+                    let restored_node = self.restored_node.take().and_then(|restored| {
+                        self.all_cluster_nodes
+                            .iter()
+                            .position(|node| *node == restored)
+                    });
                     if self.all_cluster_nodes.is_empty() {
                         self.current_node = None;
                     } else {
-                        self.current_node = Some(0);
+                        self.current_node = Some(restored_node.unwrap_or(0));
                     }
+                    return self.load_last_diffs();
+                }
+            }
+            Message::SourceChanged(source) => self.config.source = source,
+            Message::FlakeRefChanged(changed) => self.config.flake_ref = changed,
+            Message::IpAttrChanged(changed) => self.config.ip_attr = changed,
+            Message::AttrRootChanged(changed) => self.config.attr_root = changed,
+            Message::ImpureToggled(impure) => self.config.nix_flags.impure = impure,
+            Message::AcceptFlakeConfigToggled(accept) => {
+                self.config.nix_flags.accept_flake_config = accept;
+            }
+            Message::OverrideInputsChanged(changed) => {
+                if let Some(override_inputs) = parse_assignments(&changed) {
+                    self.config.nix_flags.override_inputs = override_inputs;
+                }
+                self.override_inputs_input = changed;
+            }
+            Message::NixOptionsChanged(changed) => {
+                if let Some(options) = parse_assignments(&changed) {
+                    self.config.nix_flags.options = options;
+                }
+                self.nix_options_input = changed;
+            }
+            Message::NixArgsChanged(changed) => {
+                // Keeps the last complete arguments while a quote is still open.
+                if let Some(nix_args) = shlex::split(&changed) {
+                    self.config.nix_args = nix_args;
                 }
+                self.nix_args_input = changed;
+            }
+            Message::SshUserChanged(changed) => {
+                self.config.ssh.user = Some(changed.trim().to_owned()).filter(|u| !u.is_empty());
+            }
+            Message::SshPortChanged(changed) => {
+                self.config.ssh.port = changed.trim().parse().ok();
+                self.ssh_port_input = changed;
+            }
+            Message::EvalJobsToggled(enabled) => {
+                self.config.eval_jobs = enabled.then(|| self.eval_jobs_from_inputs());
+            }
+            Message::EvalWorkersChanged(changed) => {
+                self.eval_workers_input = changed;
+                if self.config.eval_jobs.is_some() {
+                    self.config.eval_jobs = Some(self.eval_jobs_from_inputs());
+                }
+            }
+            Message::EvalMemoryChanged(changed) => {
+                self.eval_memory_input = changed;
+                if self.config.eval_jobs.is_some() {
+                    self.config.eval_jobs = Some(self.eval_jobs_from_inputs());
+                }
+            }
+            Message::NodeNameChange(idx, node) => {
+                debug!("Selected node {node}");
+                self.current_node = Some(idx);
             }
-            Message::IpAttrChanged(changed) => self.ip_attr = changed,
-            Message::NodeNameChange(idx, _) => self.current_node = Some(idx),
             Message::Error(err) => self.error = Some(err.clone()),
-            Message::NodeDiffMessage(idx, msg) => {
+            Message::NodeDiff(idx, msg) => {
                 if let Some(view) = self.node_diff_views.get_mut(idx) {
-                    return view
-                        .update(msg)
-                        .map(move |msg| Message::NodeDiffMessage(idx, msg));
+                    let task = view
+                        .update(msg, &self.config, scheduler)
+                        .map(move |msg| Message::NodeDiff(idx, msg));
+                    self.refresh_node_entries();
+                    return task;
                 }
             }
             Message::DiffAll => {
-                let diff_tasks = self
-                    .node_diff_views
-                    .iter_mut()
-                    .enumerate()
-                    .map(|(i, view)| (i, view.update(super::nix_diff::Message::StartDiff)))
-                    .map(|(idx, task)| task.map(move |msg| Message::NodeDiffMessage(idx, msg)));
-
-                return Task::batch(diff_tasks);
+                if self.batch_task.is_none() {
+                    return self.start_batch(scheduler);
+                }
+            }
+            Message::Batch(BatchEvent::Stage(stage)) => {
+                if let Some(progress) = &mut self.batch_progress {
+                    progress.stage_started(stage);
+                }
+            }
+            Message::Batch(BatchEvent::Log(line)) => push_log_line(&mut self.batch_log, line),
+            Message::Batch(BatchEvent::Build(event)) => {
+                if let Some(line) = event.text_line() {
+                    push_log_line(&mut self.batch_log, line);
+                }
+                if let Some(progress) = &mut self.batch_progress {
+                    progress.nix_event(&event);
+                }
+            }
+            Message::Batch(BatchEvent::Evaluated(node)) => {
+                self.evaluations
+                    .insert(node.node, node.result.map(|evaluated| evaluated.target));
+            }
+            Message::Batch(BatchEvent::Finished(prepared)) => {
+                self.batch_task = None;
+                self.batch_progress = None;
+                return self.start_all_diffs(prepared, scheduler);
+            }
+            Message::BatchFailed(err) => {
+                error!("Failed to prepare all nodes: {err:?}");
+                self.batch_task = None;
+                self.batch_progress = None;
+                self.batch_error = Some(err);
+                self.batch_error_expanded = false;
+                return self.start_all_diffs(BTreeMap::new(), scheduler);
+            }
+            Message::ToggleBatchLog => self.batch_log_expanded = !self.batch_log_expanded,
+            Message::ToggleBatchErrorDetails => {
+                self.batch_error_expanded = !self.batch_error_expanded;
+            }
+            Message::CancelAll => {
+                if let Some(batch_task) = self.batch_task.take() {
+                    batch_task.abort();
+                    self.batch_progress = None;
+                }
+                for view in &mut self.node_diff_views {
+                    // Cancelling never starts new work, so there's no task to run.
+                    let _ = view.update(
+                        super::nix_diff::Message::CancelDiff,
+                        &self.config,
+                        scheduler,
+                    );
+                }
+                self.refresh_node_entries();
+            }
+            Message::MoveUp(idx) => {
+                if let Some(job) = self.node_diff_views.get(idx).and_then(|view| view.job()) {
+                    scheduler.move_up(job);
+                }
+            }
+            Message::Prioritize(idx) => {
+                if let Some(job) = self.node_diff_views.get(idx).and_then(|view| view.job()) {
+                    scheduler.prioritize(job);
+                }
             }
         }
         Task::none()
     }
 
-    pub fn view(&self) -> Element<Message> {
+    pub fn view<'a>(
+        &'a self,
+        library: &'a ClusterLibrary,
+        scheduler: &Scheduler,
+    ) -> Element<'a, Message> {
         let settings_header = text("Cluster Settings").width(Length::Fill).center();
 
         let cluster_dir_header = text("Nix Hive Location:");
 
-        let base_dir = self.cluster_path.as_os_str().to_string_lossy();
+        let profile_names: Vec<String> = library.profiles.iter().map(|p| p.name.clone()).collect();
+        let selected_profile = profile_names
+            .iter()
+            .find(|name| **name == self.profile_name)
+            .cloned();
+        let profile_picker = pick_list(profile_names, selected_profile, Message::LoadProfile)
+            .placeholder("Profiles");
+        let profile_name_input =
+            text_input("Profile Name", &self.profile_name).on_input(Message::ProfileNameChanged);
+        let profile_name = self.profile_name.trim();
+        let save_profile_btn = button("Save")
+            .on_press_maybe((!profile_name.is_empty()).then_some(Message::SaveProfile));
+        let delete_profile_btn = button("Delete").on_press_maybe(
+            library
+                .profiles
+                .iter()
+                .any(|p| p.name == profile_name)
+                .then_some(Message::DeleteProfile),
+        );
+        let profile_row = row![
+            profile_picker,
+            profile_name_input,
+            save_profile_btn,
+            delete_profile_btn
+        ]
+        .spacing(5);
+
+        let base_dir = self.config.cluster_path.as_os_str().to_string_lossy();
         let mut cluster_dir_input = text_input("Cluster Directory", base_dir.as_ref());
         let mut cluster_dir_pick_btn = button("Browse");
-        if !self.loading_cluster {
+        let recent_clusters: Vec<RecentCluster> = library
+            .recent_clusters
+            .iter()
+            .cloned()
+            .map(RecentCluster)
+            .collect();
+        let recent_picker = pick_list(recent_clusters, None::<RecentCluster>, Message::OpenRecent)
+            .placeholder("Recent");
+        if !self.loading_cluster && !self.is_diffing() {
             cluster_dir_input = cluster_dir_input
                 .on_input(Message::ClusterPathChanged)
                 .on_submit(Message::StartUpdateClusterInfo);
             cluster_dir_pick_btn = cluster_dir_pick_btn.on_press(Message::PickClusterDir);
         }
-        let cluster_dir_picker = row![cluster_dir_input, cluster_dir_pick_btn];
+        let cluster_dir_picker = row![cluster_dir_input, cluster_dir_pick_btn, recent_picker];
 
-        let cluster_dir_group =
-            iced::widget::column![settings_header, cluster_dir_header, cluster_dir_picker];
+        let cluster_dir_group = iced::widget::column![
+            settings_header,
+            profile_row,
+            cluster_dir_header,
+            cluster_dir_picker
+        ];
+
+        let source_header = text("Cluster Source:");
+        let source_picker = pick_list(
+            SourceKind::ALL,
+            Some(self.config.source),
+            Message::SourceChanged,
+        );
+
+        let flake_ref_header = text("Flake Reference:");
+        let flake_ref_input = text_input("Cluster directory", &self.config.flake_ref)
+            .on_input(Message::FlakeRefChanged);
 
         let ip_attr_header = text("Node IP Address Attribute Location:");
         let ip_attr_input =
-            text_input("Attribute Path", &self.ip_attr).on_input(Message::IpAttrChanged);
+            text_input("Attribute Path", &self.config.ip_attr).on_input(Message::IpAttrChanged);
+
+        let attr_root_header = text("Nodes Attribute:");
+        let attr_root_input = text_input(
+            self.config.source().default_attr_root(),
+            &self.config.attr_root,
+        )
+        .on_input(Message::AttrRootChanged);
+
+        let nix_flags = &self.config.nix_flags;
+        let nix_flags_toggles = row![
+            checkbox("Impure", nix_flags.impure).on_toggle(Message::ImpureToggled),
+            checkbox("Accept flake config", nix_flags.accept_flake_config)
+                .on_toggle(Message::AcceptFlakeConfigToggled),
+        ]
+        .spacing(10);
+        let override_inputs_header = text("Override Inputs:");
+        let override_inputs_input =
+            text_input("nixpkgs=github:NixOS/nixpkgs", &self.override_inputs_input)
+                .on_input(Message::OverrideInputsChanged);
+        let nix_options_header = text("Nix Options:");
+        let nix_options_input = text_input(
+            "builders='ssh://builder x86_64-linux' max-jobs=4",
+            &self.nix_options_input,
+        )
+        .on_input(Message::NixOptionsChanged);
+        let nix_args_header = text("Extra Nix Arguments:");
+        let nix_args_input =
+            text_input("--show-trace", &self.nix_args_input).on_input(Message::NixArgsChanged);
+        let nix_args_preview = text!(
+            "Every nix command gets: {}",
+            shlex::try_join(self.config.nix_args(&[]).iter().map(String::as_str))
+                .unwrap_or_default()
+        )
+        .size(12);
 
-        let ip_attr_group = container(iced::widget::column![ip_attr_header, ip_attr_input])
-            .padding(Padding::ZERO.bottom(5).top(5));
+        let ssh_header = text("SSH User / Port Override:");
+        let ssh_user_input = text_input(
+            "User from ssh config",
+            self.config.ssh.user.as_deref().unwrap_or_default(),
+        )
+        .on_input(Message::SshUserChanged);
+        let ssh_port_input = text_input("Port from ssh config", &self.ssh_port_input)
+            .on_input(Message::SshPortChanged);
+
+        let eval_jobs_toggle = checkbox(
+            "Evaluate with nix-eval-jobs",
+            self.config.eval_jobs.is_some(),
+        )
+        .on_toggle(Message::EvalJobsToggled);
+        let eval_jobs_inputs = self.config.eval_jobs.is_some().then(|| {
+            row![
+                text("Workers:"),
+                text_input("4", &self.eval_workers_input).on_input(Message::EvalWorkersChanged),
+                text("Max MiB per worker:"),
+                text_input("4096", &self.eval_memory_input).on_input(Message::EvalMemoryChanged),
+            ]
+            .spacing(5)
+            .align_y(Alignment::Center)
+        });
+
+        let ip_attr_group = container(
+            iced::widget::column![
+                source_header,
+                source_picker,
+                flake_ref_header,
+                flake_ref_input,
+                ip_attr_header,
+                ip_attr_input,
+                attr_root_header,
+                attr_root_input,
+                nix_flags_toggles,
+                override_inputs_header,
+                override_inputs_input,
+                nix_options_header,
+                nix_options_input,
+                nix_args_header,
+                nix_args_input,
+                nix_args_preview,
+                ssh_header,
+                row![ssh_user_input, ssh_port_input].spacing(5),
+                eval_jobs_toggle,
+            ]
+            .push_maybe(eval_jobs_inputs)
+            .spacing(2),
+        )
+        .padding(Padding::ZERO.bottom(5).top(5));
 
         let node_name_header = text("Nodes").width(Length::Fill).center();
-        let node_diff_all = container(button("Diff All").on_press(Message::DiffAll))
-            .padding(Padding::ZERO.bottom(5).top(5));
+        let node_diff_all = container(
+            button("Diff All")
+                .on_press_maybe(self.batch_task.is_none().then_some(Message::DiffAll)),
+        )
+        .padding(Padding::ZERO.bottom(5).top(5));
         let currently_diffing = self
             .node_diff_views
             .iter()
@@ -147,25 +521,65 @@ impl NixClusterView {
         } else {
             None
         };
-        let node_name_picker = selection_list(&self.all_cluster_nodes[..], Message::NodeNameChange);
-        let diff_all_row = row![node_diff_all].push_maybe(node_diff_count);
+        let node_name_picker = selection_list(&self.node_entries[..], Message::NodeNameChange);
+        let batch_label = self.batch_progress.as_ref().map(|progress| {
+            let mut label = format!(
+                "All nodes: {} ({})",
+                progress.stage().label(),
+                format::duration(progress.elapsed())
+            );
+            if progress.stage() == DiffStage::EvaluatingIp && !self.evaluations.is_empty() {
+                let _ = write!(
+                    label,
+                    ", {} of {} nodes evaluated",
+                    self.evaluations.len(),
+                    self.batch_size
+                );
+            }
+            if progress.stage() == DiffStage::BuildingToplevel {
+                let build = progress.nix();
+                let _ = write!(
+                    label,
+                    ", {} builds and {} downloads remaining",
+                    build.builds_remaining(),
+                    build.downloads_remaining()
+                );
+            }
+            text(label).size(12)
+        });
+        let cancel_all = self.is_diffing().then(|| {
+            container(button("Cancel All").on_press(Message::CancelAll))
+                .padding(Padding::ZERO.bottom(5).top(5))
+        });
+        let diff_all_row = row![node_diff_all]
+            .push_maybe(cancel_all)
+            .push_maybe(node_diff_count)
+            .spacing(5);
 
         let error = text(self.error.as_deref().unwrap_or(""))
             .color(Color::new(1.0, 0.2, 0.2, 1.0))
             .width(Length::Fill)
             .center();
 
-        let node_name_group =
-            container(column![node_name_header, diff_all_row, node_name_picker]).padding(5);
+        let node_name_group = container(
+            column![node_name_header, diff_all_row]
+                .push_maybe(batch_label)
+                .push_maybe(self.batch_error_view())
+                .push_maybe(self.batch_log_view())
+                .push_maybe(self.evaluation_view())
+                .push(node_name_picker)
+                .push_maybe(self.queue_view(scheduler)),
+        )
+        .padding(5);
 
         let mut settings_and_node = column![cluster_dir_group, ip_attr_group, error]
             .width(Length::FillPortion(3))
             .padding(5);
         if let Some(idx) = self.current_node {
-            let current_node = self
-                .node_diff_views
-                .get(idx)
-                .map(|n| n.view().map(move |msg| Message::NodeDiffMessage(idx, msg)));
+            let current_node = self.node_diff_views.get(idx).map(|n| {
+                n.view(&self.config, scheduler)
+                    .map(move |msg| Message::NodeDiff(idx, msg))
+            });
             let node_view = current_node.map(|node| {
                 let node_header = text(format!("Node Diff View {}", self.all_cluster_nodes[idx]))
                     .width(Length::Fill)
@@ -177,21 +591,333 @@ impl NixClusterView {
 
         row![node_name_group, settings_and_node].into()
     }
+}
+
+impl NixClusterView {
+    /// The cluster's nodes waiting for the scheduler, with buttons to reorder them.
+    fn queue_view(&self, scheduler: &Scheduler) -> Option<Element<'_, Message>> {
+        let mut queued: Vec<_> = self
+            .node_diff_views
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, view)| Some((view.queued(scheduler)?, idx, view.node_name())))
+            .collect();
+        if queued.is_empty() {
+            return None;
+        }
+        queued.sort_by_key(|((kind, position), _, _)| (*kind as usize, *position));
+
+        let rows = queued.into_iter().fold(
+            column![text("Queue")].spacing(2),
+            |rows, ((kind, position), idx, node_name)| {
+                let label = text!("#{position} {} {node_name}", kind.label())
+                    .size(12)
+                    .width(Length::Fill);
+                let up_btn = button(text("Up").size(12))
+                    .on_press_maybe((position > 1).then_some(Message::MoveUp(idx)));
+                let top_btn = button(text("Top").size(12))
+                    .on_press_maybe((position > 1).then_some(Message::Prioritize(idx)));
+                rows.push(row![label, up_btn, top_btn].spacing(2))
+            },
+        );
+
+        Some(container(rows).padding(Padding::ZERO.top(5)).into())
+    }
+
+    /// Why "Diff All" couldn't evaluate and build the nodes together.
+    fn batch_error_view(&self) -> Option<Element<'_, Message>> {
+        let err = self.batch_error.as_ref()?;
+        let note =
+            text("Diff All fell back to evaluating and building every node on its own").size(12);
+        let card = error_card(
+            err,
+            self.batch_error_expanded,
+            Message::ToggleBatchErrorDetails,
+        );
+        Some(column![card, note].spacing(5).into())
+    }
+
+    /// Stderr of evaluating and building all nodes, once "Diff All" ran any command.
+    fn batch_log_view(&self) -> Option<Element<'_, Message>> {
+        (!self.batch_log.is_empty()).then(|| {
+            log_pane(
+                &self.batch_log,
+                self.batch_log_expanded,
+                Message::ToggleBatchLog,
+            )
+        })
+    }
+
+    /// Per node evaluation results of "Diff All". Every node is listed while evaluating,
+    /// afterwards only the ones that failed.
+    fn evaluation_view(&self) -> Option<Element<'_, Message>> {
+        let evaluating = self
+            .batch_progress
+            .as_ref()
+            .is_some_and(|progress| progress.stage() == DiffStage::EvaluatingIp);
+        let shown: Vec<_> = self
+            .evaluations
+            .iter()
+            .filter(|(_, result)| evaluating || result.is_err())
+            .collect();
+        if shown.is_empty() {
+            return None;
+        }
+
+        let rows = shown.into_iter().fold(
+            column![text("Evaluation")].spacing(2),
+            |rows, (node, result)| match result {
+                Ok(target) => rows.push(
+                    text!("✓ {node}: {} (from {})", target.host, target.source.label()).size(12),
+                ),
+                Err(err) => {
+                    let summary = err
+                        .lines()
+                        .rfind(|line| !line.trim().is_empty())
+                        .unwrap_or(err);
+                    rows.push(
+                        text!("✗ {node}: {}", summary.trim())
+                            .size(12)
+                            .color(Color::new(1.0, 0.2, 0.2, 1.0)),
+                    )
+                }
+            },
+        );
+
+        Some(container(rows).padding(Padding::ZERO.top(5)).into())
+    }
+
+    /// nix-eval-jobs settings from the inputs, defaults for anything that isn't a number.
+    fn eval_jobs_from_inputs(&self) -> EvalJobs {
+        let defaults = EvalJobs::default();
+        EvalJobs {
+            workers: self
+                .eval_workers_input
+                .trim()
+                .parse()
+                .unwrap_or(defaults.workers),
+            max_memory_mib: self
+                .eval_memory_input
+                .trim()
+                .parse()
+                .unwrap_or(defaults.max_memory_mib),
+        }
+    }
+
+    fn set_config(&mut self, config: ClusterConfig) {
+        let eval_jobs = config.eval_jobs.unwrap_or_default();
+        self.eval_workers_input = eval_jobs.workers.to_string();
+        self.eval_memory_input = eval_jobs.max_memory_mib.to_string();
+        self.override_inputs_input = join_assignments(&config.nix_flags.override_inputs);
+        self.nix_options_input = join_assignments(&config.nix_flags.options);
+        self.nix_args_input =
+            shlex::try_join(config.nix_args.iter().map(String::as_str)).unwrap_or_default();
+        self.ssh_port_input = config
+            .ssh
+            .port
+            .map(|port| port.to_string())
+            .unwrap_or_default();
+        self.config = config;
+    }
+
+    /// Creates a cluster view from persisted state, loading its nodes if a cluster was set.
+    pub fn restore(state: &ClusterState) -> (Self, Task<Message>) {
+        let mut view = Self {
+            profile_name: state.profile_name.clone(),
+            restored_ip_attrs: state.node_ip_attrs.clone(),
+            restored_node: state.selected_node.clone(),
+            restored_last_diffs: state.last_diffs.clone(),
+            ..Self::default()
+        };
+        view.set_config(state.config.clone());
+
+        if view.config.cluster_path.as_os_str().is_empty() {
+            return (view, Task::none());
+        }
+        let task = view.start_cluster_info_update();
+        (view, task)
+    }
+
+    pub fn persist(&self) -> ClusterState {
+        let node_ip_attrs = if self.node_diff_views.is_empty() {
+            self.restored_ip_attrs.clone()
+        } else {
+            self.node_diff_views
+                .iter()
+                .filter_map(|view| Some((view.node_name().to_owned(), view.ip_attr()?.to_owned())))
+                .collect()
+        };
+        let last_diffs = if self.node_diff_views.is_empty() {
+            self.restored_last_diffs.clone()
+        } else {
+            self.node_diff_views
+                .iter()
+                .filter_map(|view| Some((view.node_name().to_owned(), view.last_diff()?)))
+                .collect()
+        };
+        let selected_node = self
+            .current_node
+            .and_then(|idx| self.all_cluster_nodes.get(idx).cloned())
+            .or_else(|| self.restored_node.clone());
+
+        ClusterState {
+            config: self.config.clone(),
+            profile_name: self.profile_name.clone(),
+            node_ip_attrs,
+            selected_node,
+            last_diffs,
+        }
+    }
+
+    pub fn is_diffing(&self) -> bool {
+        self.batch_task.is_some() || self.node_diff_views.iter().any(NixNodeDiffView::is_diffing)
+    }
+
+    /// Evaluates and builds every node that isn't being diffed yet with one nix invocation each.
+    fn start_batch(&mut self, scheduler: &Scheduler) -> Task<Message> {
+        let nodes: Vec<_> = self
+            .node_diff_views
+            .iter()
+            .filter(|view| !view.is_diffing())
+            .map(|view| BatchNode {
+                name: view.node_name().to_owned(),
+                ip_attr: view.config(&self.config).ip_attr,
+            })
+            .collect();
+        if nodes.is_empty() {
+            return Task::none();
+        }
+        self.batch_size = nodes.len();
+
+        let batch = prepare_nodes(self.config.clone(), nodes, scheduler.job());
+        let (task, handle) = Task::stream(batch)
+            .map(|event| match event {
+                Ok(event) => Message::Batch(event),
+                Err(err) => Message::BatchFailed(err),
+            })
+            .abortable();
+        // Dropped along with the view when its cluster is closed or reloaded.
+        self.batch_task = Some(handle.abort_on_drop());
+        self.batch_progress = Some(DiffProgress::default());
+        self.batch_log.clear();
+        self.batch_error = None;
+        self.evaluations.clear();
+
+        task
+    }
+
+    /// Diffs every node that isn't being diffed yet, skipping evaluation and build for the
+    /// `prepared` ones.
+    fn start_all_diffs(
+        &mut self,
+        mut prepared: BTreeMap<String, PreparedNode>,
+        scheduler: &Scheduler,
+    ) -> Task<Message> {
+        let cluster = &self.config;
+        let diff_tasks = self
+            .node_diff_views
+            .iter_mut()
+            .enumerate()
+            .map(|(idx, view)| {
+                let start = match prepared.remove(view.node_name()) {
+                    Some(node) => super::nix_diff::Message::StartPreparedDiff(node),
+                    None => super::nix_diff::Message::StartDiff,
+                };
+                view.update(start, cluster, scheduler)
+                    .map(move |msg| Message::NodeDiff(idx, msg))
+            });
+
+        let task = Task::batch(diff_tasks);
+        self.refresh_node_entries();
+        task
+    }
+
+    /// Shows the restored last diff of every node again, if it's still cached.
+    fn load_last_diffs(&mut self) -> Task<Message> {
+        let mut last_diffs = std::mem::take(&mut self.restored_last_diffs);
+        let tasks = self
+            .node_diff_views
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, view)| {
+                let key = last_diffs.remove(view.node_name())?;
+                let load = Task::future(unblock(move || cache::load(&key)));
+                Some(load.and_then(move |diff| {
+                    Task::done(Message::NodeDiff(
+                        idx,
+                        super::nix_diff::Message::RestoredResult(diff),
+                    ))
+                }))
+            });
+
+        Task::batch(tasks)
+    }
+
+    fn refresh_node_entries(&mut self) {
+        self.node_entries = self
+            .node_diff_views
+            .iter()
+            .map(|view| NodeEntry {
+                name: view.node_name().to_owned(),
+                status: view.status(),
+            })
+            .collect();
+    }
+
+    /// Name shown in the workspace: the profile name, or the directory of the cluster.
+    pub fn display_name(&self) -> String {
+        let profile_name = self.profile_name.trim();
+        if !profile_name.is_empty() {
+            return profile_name.to_owned();
+        }
+
+        let path = &self.config.cluster_path;
+        let dir = if path.is_file() {
+            path.parent()
+        } else {
+            Some(path.as_path())
+        };
+        dir.and_then(|dir| dir.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "New Cluster".to_owned())
+    }
 
     pub fn start_cluster_info_update(&mut self) -> Task<Message> {
         self.loading_cluster = true;
         self.all_cluster_nodes.clear();
         self.node_diff_views.clear();
+        self.node_entries.clear();
 
-        let cluster_path = self.cluster_path.clone();
-
-        Task::future(fetch_cluster_nodes(cluster_path)).then(|res| match res {
+        let config = self.config.clone();
+        Task::future(async move { source::list_nodes(&config).await }).then(|res| match res {
             Ok(nodes) => Task::done(Message::UpdateClusterInfo(Some(nodes))),
             Err(err) => {
                 error!("Couldn't update cluster nodes: {err:?}");
-                let err = err.to_string();
+                let err = format!("{err}\n{}", err.hint());
                 Task::done(Message::UpdateClusterInfo(None)).chain(Task::done(Message::Error(err)))
             }
         })
     }
 }
+
+/// Parses shell quoted `name=value` words, None while a quote is still open. Words without a
+/// `=` are skipped.
+fn parse_assignments(input: &str) -> Option<BTreeMap<String, String>> {
+    let words = shlex::split(input)?;
+    Some(
+        words
+            .iter()
+            .filter_map(|word| word.split_once('='))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect(),
+    )
+}
+
+/// The inverse of [`parse_assignments`].
+fn join_assignments(assignments: &BTreeMap<String, String>) -> String {
+    let words: Vec<_> = assignments
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+    shlex::try_join(words.iter().map(String::as_str)).unwrap_or_default()
+}
@@ -1,32 +1,55 @@
-use anyhow::{Context, bail};
-use async_stream::stream;
-use duct::cmd;
-use futures::Stream;
+use crate::nix::ClusterConfig;
+use crate::nix::batch::PreparedNode;
+use crate::nix::build_log::BuildEvent;
+use crate::nix::cache::DiffKey;
+use crate::nix::diff::{DiffEvent, DiffStage, NodeDiff, NodeStatus, run_diff};
+use crate::nix::error::DiffError;
+use crate::nix::history::{self, HistoryEntry};
+use crate::nix::process::unblock;
+use crate::nix::progress::DiffProgress;
+use crate::nix::scheduler::{JobId, JobKind, Scheduler};
+use crate::nix::source::Target;
+use crate::pages::nix_diff::cache::DiffCache;
+use crate::utils::format;
+use iced::task;
 use iced::widget::{
-    button, column, container, progress_bar, rich_text, scrollable,
-    text, text_input,
+    button, column, container, progress_bar, rich_text, row, scrollable, text, text_input,
 };
 use iced::{Color, Element, Font, Length, Padding, Task};
-use log::{debug, error};
-use ssh2_config::{ParseRule, SshConfig};
-use std::net::{IpAddr, TcpStream};
-use std::path::{Path, PathBuf};
-use crate::pages::nix_diff::cache::DiffCache;
+use log::error;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::mem;
 
 #[derive(Debug, Clone)]
 pub enum Message {
     StartDiff,
+    /// Starts a diff of a node that was already evaluated and built along with others.
+    StartPreparedDiff(PreparedNode),
+    CancelDiff,
     IpAttrChanged(String),
-    DiffResult(Option<String>),
-    Error(String),
-    DiffProgress(f32),
+    DiffResult(NodeDiff),
+    /// Ends a diff that failed, the last message of its stream.
+    DiffFailed(DiffError),
+    /// Shows a diff restored from the cache, unless a new one is already running.
+    RestoredResult(NodeDiff),
+    ToggleErrorDetails,
+    DiffProgress(DiffStage),
+    TargetResolved(Target),
+    LogLine(String),
+    ToggleLog,
+    Build(BuildEvent),
+    ToggleHistory,
+    HistoryLoaded(Vec<HistoryEntry>),
+    /// Shows a past diff from the history, by its index.
+    OpenHistoryEntry(usize),
 }
 
 mod cache {
-    use std::mem;
-    use iced::advanced::text::Span;
     use crate::pages::nix_diff::Message;
     use crate::utils::ansi_to_rich::{ansi_to_spans, make_spans};
+    use iced::advanced::text::Span;
+    use std::mem;
 
     // Uh-uh.. No touching.
     // This is locked away because it's self-referential.
@@ -39,7 +62,7 @@ mod cache {
         pub fn new(diff: String) -> Self {
             unsafe {
                 let static_diff: &'static str = mem::transmute(diff.as_str());
-                let raw_spans = ansi_to_spans(&static_diff);
+                let raw_spans = ansi_to_spans(static_diff);
                 let spans = make_spans(&raw_spans);
 
                 Self { _raw: diff, spans }
@@ -52,93 +75,292 @@ mod cache {
     }
 }
 
+/// Oldest lines are dropped from a command log beyond this.
+const MAX_LOG_LINES: usize = 5000;
+
 pub struct NixNodeDiffView {
-    node_path: PathBuf,
-    ip_attr: String,
+    /// The node's own IP address attribute, the cluster's is used without one.
+    ip_attr: Option<String>,
     node_name: String,
     diff: Option<DiffCache>,
+    /// The last finished diff. Its nvd output was moved into `diff`.
+    result: Option<NodeDiff>,
     loading_diff: bool,
-    error: Option<String>,
-    diff_progress: f32,
+    /// Aborts the running diff, dropping its stream and killing the command it's waiting on.
+    diff_task: Option<task::Handle>,
+    /// The running diff's place in the scheduler.
+    job: Option<JobId>,
+    error: Option<DiffError>,
+    error_expanded: bool,
+    progress: DiffProgress,
+    /// Where the node was reached by the last diff.
+    target: Option<Target>,
+    /// Stderr of the commands run for the last diff.
+    log: VecDeque<String>,
+    log_expanded: bool,
+    /// Past diffs of the node, newest first. Only loaded while the history is shown.
+    history: Vec<HistoryEntry>,
+    history_expanded: bool,
+    /// When the shown diff was checked, if it was opened from the history.
+    opened_from_history: Option<u64>,
 }
 
 impl NixNodeDiffView {
     pub fn is_diffing(&self) -> bool {
         self.loading_diff
     }
+
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    /// The node's own IP address attribute, if it has one.
+    pub fn ip_attr(&self) -> Option<&str> {
+        self.ip_attr.as_deref()
+    }
+
+    /// The cluster's current settings with the node's own IP address attribute applied, so
+    /// every diff runs with the settings as they are when it starts.
+    pub fn config(&self, cluster: &ClusterConfig) -> ClusterConfig {
+        let mut config = cluster.clone();
+        if let Some(ip_attr) = &self.ip_attr {
+            config.ip_attr.clone_from(ip_attr);
+        }
+        config
+    }
+
+    /// Outcome of the last diff, none while diffing or before the first one.
+    pub fn status(&self) -> Option<NodeStatus> {
+        if self.loading_diff {
+            None
+        } else if self.error.is_some() {
+            Some(NodeStatus::Error)
+        } else {
+            self.result.as_ref().map(|result| {
+                if result.up_to_date {
+                    NodeStatus::UpToDate
+                } else {
+                    NodeStatus::Drifted
+                }
+            })
+        }
+    }
+
+    /// The systems compared by the last finished diff.
+    pub fn last_diff(&self) -> Option<DiffKey> {
+        self.result.as_ref().map(NodeDiff::key)
+    }
+
+    pub fn job(&self) -> Option<JobId> {
+        self.job
+    }
+
+    /// The kind of work the running diff waits for and its position in that queue.
+    pub fn queued(&self, scheduler: &Scheduler) -> Option<(JobKind, usize)> {
+        scheduler.position(self.job?)
+    }
 }
 
 impl NixNodeDiffView {
-    pub fn new(cluster_path: PathBuf, ip_attr: String, node_name: String) -> Self {
+    pub fn new(node_name: String, ip_attr: Option<String>) -> Self {
         Self {
-            node_path: cluster_path,
             ip_attr,
             node_name,
             diff: None,
+            result: None,
             loading_diff: false,
+            diff_task: None,
+            job: None,
             error: None,
-            diff_progress: 0.0,
+            error_expanded: false,
+            progress: DiffProgress::default(),
+            target: None,
+            log: VecDeque::new(),
+            log_expanded: false,
+            history: Vec::new(),
+            history_expanded: false,
+            opened_from_history: None,
         }
     }
 }
 
 impl NixNodeDiffView {
-    pub fn update(&mut self, message: Message) -> Task<Message> {
+    pub fn update(
+        &mut self,
+        message: Message,
+        cluster: &ClusterConfig,
+        scheduler: &Scheduler,
+    ) -> Task<Message> {
         match message {
             Message::StartDiff => {
                 if !self.loading_diff {
-                    return self.run_diff_task();
+                    return self.run_diff_task(cluster, scheduler, None);
                 }
             }
-            Message::IpAttrChanged(mut ip_attr) => {
-                if ip_attr.is_empty() {
-                    ip_attr = "config.base.primaryIP.address".to_owned();
+            Message::StartPreparedDiff(prepared) => {
+                if !self.loading_diff {
+                    return self.run_diff_task(cluster, scheduler, Some(prepared));
+                }
+            }
+            Message::IpAttrChanged(ip_attr) => {
+                self.ip_attr = Some(ip_attr).filter(|ip_attr| !ip_attr.is_empty());
+            }
+            Message::CancelDiff => {
+                if let Some(diff_task) = self.diff_task.take() {
+                    diff_task.abort();
+                    self.job = None;
+                    self.loading_diff = false;
+                    self.progress = DiffProgress::default();
+                    self.push_log("Diff cancelled".to_owned());
                 }
-                self.ip_attr = ip_attr;
             }
             Message::DiffResult(diff) => {
                 self.loading_diff = false;
+                self.diff_task = None;
+                self.job = None;
                 self.error = None;
-                self.diff = diff.map(DiffCache::new);
+                self.show_result(Some(diff));
+                if self.history_expanded {
+                    return self.load_history(cluster);
+                }
             }
-            Message::DiffProgress(progress) => {
-                self.diff_progress = progress;
+            Message::DiffFailed(err) => {
+                self.loading_diff = false;
+                self.diff_task = None;
+                self.job = None;
+                self.progress = DiffProgress::default();
+                self.show_result(None);
+                self.error = Some(err);
+                self.error_expanded = false;
             }
-            Message::Error(err) => {
-                self.error = Some(err.to_string());
+            Message::RestoredResult(diff) => {
+                if !self.loading_diff {
+                    self.show_result(Some(diff));
+                }
+            }
+            Message::DiffProgress(stage) => {
+                self.progress.stage_started(stage);
+            }
+            Message::ToggleErrorDetails => {
+                self.error_expanded = !self.error_expanded;
+            }
+            Message::TargetResolved(target) => self.target = Some(target),
+            Message::LogLine(line) => self.push_log(line),
+            Message::ToggleLog => {
+                self.log_expanded = !self.log_expanded;
+            }
+            Message::ToggleHistory => {
+                self.history_expanded = !self.history_expanded;
+                if self.history_expanded {
+                    return self.load_history(cluster);
+                }
+            }
+            Message::HistoryLoaded(history) => self.history = history,
+            Message::OpenHistoryEntry(idx) => {
+                if let Some(entry) = self.history.get(idx).filter(|_| !self.loading_diff) {
+                    let checked_at = entry.checked_at;
+                    self.error = None;
+                    self.show_result(Some(entry.diff.clone()));
+                    self.opened_from_history = Some(checked_at);
+                }
+            }
+            Message::Build(event) => {
+                if let Some(line) = event.text_line() {
+                    self.push_log(line);
+                }
+                self.progress.nix_event(&event);
             }
         }
 
         Task::none()
     }
 
-    pub fn view(&self) -> Element<Message> {
-        let ip_attr_header = text("Node IP Address Attribute Location:");
-        let ip_attr_input =
-            text_input("Attribute Path", &self.ip_attr).on_input(Message::IpAttrChanged);
+    fn show_result(&mut self, diff: Option<NodeDiff>) {
+        self.diff = None;
+        self.opened_from_history = None;
+        self.result = diff.map(|mut diff| {
+            if !diff.up_to_date {
+                self.diff = Some(DiffCache::new(mem::take(&mut diff.diff)));
+            }
+            diff
+        });
+    }
 
-        let ip_attr_group = container(column![ip_attr_header, ip_attr_input])
-            .padding(Padding::ZERO.bottom(5).top(5));
+    fn load_history(&self, cluster: &ClusterConfig) -> Task<Message> {
+        let cluster_file = cluster.cluster_file();
+        let node_name = self.node_name.clone();
+        Task::perform(
+            unblock(move || history::load(&cluster_file, &node_name)),
+            Message::HistoryLoaded,
+        )
+    }
 
-        let mut run_diff_btn = button("Run Diff");
-        if !self.loading_diff {
-            run_diff_btn = run_diff_btn.on_press(Message::StartDiff);
-        }
+    fn push_log(&mut self, line: String) {
+        push_log_line(&mut self.log, line);
+    }
 
-        let progress_bar = progress_bar(0.0..=10.0, self.diff_progress).height(Length::Fixed(5.));
+    pub fn view<'a>(
+        &'a self,
+        cluster: &'a ClusterConfig,
+        scheduler: &Scheduler,
+    ) -> Element<'a, Message> {
+        let ip_attr_header = text("Node IP Address Attribute Location:");
+        let ip_attr_group = match cluster.source().target_description() {
+            Some(description) => column![ip_attr_header, text(description).size(12)],
+            // Left empty, the node follows the cluster's attribute shown as the placeholder.
+            None => column![
+                ip_attr_header,
+                text_input(
+                    &cluster.ip_attr,
+                    self.ip_attr.as_deref().unwrap_or_default()
+                )
+                .on_input(Message::IpAttrChanged)
+            ],
+        };
+        let target_label = self.target.as_ref().map(|target| {
+            text!(
+                "Reached at {} (from {})",
+                target.host,
+                target.source.label()
+            )
+            .size(12)
+        });
+        let ip_attr_group = container(ip_attr_group.push_maybe(target_label))
+            .padding(Padding::ZERO.bottom(5).top(5));
 
-        let error_txt = text(self.error.as_deref().unwrap_or(""))
-            .color(Color::new(1.0, 0.2, 0.2, 1.0))
-            .width(Length::Fill)
-            .center();
+        let run_diff_btn = if self.loading_diff {
+            button("Cancel").on_press(Message::CancelDiff)
+        } else {
+            button("Run Diff").on_press(Message::StartDiff)
+        };
 
-        let top =
-            container(column![ip_attr_group, run_diff_btn, error_txt, progress_bar,].padding(50))
-                .style(|theme| {
-                    let mut style = container::rounded_box(theme);
-                    style.background = None;
-                    style
-                });
+        let progress_bar =
+            progress_bar(0.0..=1.0, self.progress.fraction()).height(Length::Fixed(5.));
+        let progress_label = self
+            .loading_diff
+            .then(|| text(self.progress_label(scheduler)).size(12));
+
+        let build_pane = (self.loading_diff
+            && self.progress.stage() == DiffStage::BuildingToplevel)
+            .then(|| self.build_pane());
+
+        let error_card = self
+            .error
+            .as_ref()
+            .map(|err| error_card(err, self.error_expanded, Message::ToggleErrorDetails));
+
+        let top = container(
+            column![ip_attr_group, run_diff_btn, progress_bar]
+                .push_maybe(progress_label)
+                .push_maybe(build_pane)
+                .push_maybe(error_card)
+                .padding(50),
+        )
+        .style(|theme| {
+            let mut style = container::rounded_box(theme);
+            style.background = None;
+            style
+        });
 
         let diff_log = if let Some(diff) = &self.diff {
             let rich_diff = rich_text(diff.spans()).font(Font::MONOSPACE);
@@ -147,6 +369,15 @@ impl NixNodeDiffView {
                 .style(container::dark)
                 .width(Length::Fill)
                 .height(Length::Fill)
+        } else if let Some(result) = self.result.as_ref().filter(|result| result.up_to_date) {
+            container(text!(
+                "Up to date, the node already runs {}",
+                result.system_path.display()
+            ))
+            .padding(5)
+            .style(container::dark)
+            .width(Length::Fill)
+            .height(Length::Fill)
         } else {
             container(column![])
                 .padding(5)
@@ -155,185 +386,266 @@ impl NixNodeDiffView {
                 .height(Length::Fill)
         };
 
-        let main = column![top, diff_log];
+        let computed_at = self.result.as_ref().map(|result| {
+            let cached = if result.cached {
+                ", from the cache"
+            } else {
+                ""
+            };
+            let label = format!("Computed {}{cached}", format::ago(result.computed_at));
+            match self.opened_from_history {
+                Some(checked_at) => text!(
+                    "Past diff checked at {}. {label}",
+                    format::timestamp(checked_at)
+                ),
+                None => text(label),
+            }
+            .size(12)
+        });
+
+        let main = column![top]
+            .push_maybe(computed_at)
+            .push(diff_log)
+            .push(self.history_pane())
+            .push(log_pane(&self.log, self.log_expanded, Message::ToggleLog));
         container(main).into()
     }
 
-    pub fn run_diff_task(&mut self) -> Task<Message> {
+    pub fn run_diff_task(
+        &mut self,
+        cluster: &ClusterConfig,
+        scheduler: &Scheduler,
+        prepared: Option<PreparedNode>,
+    ) -> Task<Message> {
         self.loading_diff = true;
+        self.log.clear();
+        self.progress = DiffProgress::default();
+        self.target = None;
 
-        let cluster_path = self.node_path.clone();
+        let config = self.config(cluster);
         let node_name = self.node_name.clone();
-        let ip_attr = self.ip_attr.clone();
-
-        Task::stream(run_diff(cluster_path, node_name, ip_attr)).then(|res| match res {
-            Ok(msg) => Task::done(msg),
-            Err(err) => {
-                error!("Failed to diff: {err:?}");
-                let err = err.to_string();
-                Task::done(Message::DiffResult(None))
-                    .chain(Task::done(Message::Error(err)))
-                    .chain(Task::done(Message::DiffProgress(0.0)))
-            }
-        })
-    }
-}
-
-fn ip_from_node(cluster_path: &PathBuf, node_name: &str, ip_attr: &str) -> anyhow::Result<IpAddr> {
-    let args = [
-        "eval",
-        &format!(".#nixosConfigurations.{node_name}.{ip_attr}"),
-        "--json",
-    ];
+        let job = scheduler.job();
+        self.job = Some(job.id());
+
+        let (task, handle) = Task::stream(run_diff(config, node_name, job, prepared))
+            .then(|res| match res {
+                Ok(DiffEvent::Stage(stage)) => Task::done(Message::DiffProgress(stage)),
+                Ok(DiffEvent::Target(target)) => Task::done(Message::TargetResolved(target)),
+                Ok(DiffEvent::Log(line)) => Task::done(Message::LogLine(line)),
+                Ok(DiffEvent::Build(event)) => Task::done(Message::Build(event)),
+                Ok(DiffEvent::Finished(diff)) => Task::done(Message::DiffResult(diff)),
+                Err(err) => {
+                    error!("Failed to diff: {err:?}");
+                    Task::done(Message::DiffFailed(err))
+                }
+            })
+            .abortable();
+        // Dropped along with the view when its cluster is closed or reloaded.
+        self.diff_task = Some(handle.abort_on_drop());
 
-    let ip_json = run_nix_command_in_dir(&cluster_path, &args)?;
+        task
+    }
 
-    let ip_str = serde_json::from_str::<String>(&ip_json)
-        .with_context(|| format!("Couldn't parse JSON {ip_json:?}"))?;
+    /// Current stage with its elapsed time, bytes copied during the closure copy and the ETA.
+    fn progress_label(&self, scheduler: &Scheduler) -> String {
+        let stage = self.progress.stage();
+        if let Some((kind, position)) = self.queued(scheduler) {
+            return format!(
+                "{} - queued for a free {} slot (#{position})",
+                stage.label(),
+                kind.label()
+            );
+        }
 
-    Ok(ip_str.parse()?)
-}
+        let mut label = format!(
+            "{} ({})",
+            stage.label(),
+            format::duration(self.progress.stage_elapsed())
+        );
+
+        let (copied, expected) = self.progress.nix().bytes_copied();
+        if stage == DiffStage::CopyingClosure && expected > 0 {
+            let _ = write!(
+                label,
+                " · {} of {}",
+                format::bytes(copied),
+                format::bytes(expected)
+            );
+        }
 
-pub async fn fetch_cluster_nodes(cluster_path: PathBuf) -> anyhow::Result<Vec<String>> {
-    if !cluster_path.is_dir() {
-        return fetch_nodes_from_file(&cluster_path);
-    }
+        if let Some(eta) = self.progress.eta() {
+            let _ = write!(label, " · about {} left", format::duration(eta));
+        }
 
-    let flake = cluster_path.join("flake.nix");
-    if let Ok(nodes) = fetch_nodes_from_flake(&flake) {
-        return Ok(nodes);
+        label
     }
 
-    bail!("Couldn't get proper nodes from flake.nix");
-}
-
-pub fn run_diff(
-    cluster_path: PathBuf,
-    node_name: String,
-    ip_attr: String,
-) -> impl Stream<Item = anyhow::Result<Message>> {
-    stream! {
-        yield Ok(Message::DiffProgress(0.0));
-
-        let ip = ip_from_node(&cluster_path, &node_name, &ip_attr)
-            .with_context(|| format!("Couldn't find IP Address at {node_name}.{ip_attr}"))?;
-        yield Ok(Message::DiffProgress(1.0));
-
-        let cluster_path = cluster_path
-            .parent()
-            .context("Couldn't get cluster directory")?;
-        yield Ok(Message::DiffProgress(2.0));
-
-        let new_drv: PathBuf = cmd!(
-            "nix",
-            "build",
-            format!(".#nixosConfigurations.{node_name}.config.system.build.toplevel"),
-            "--print-out-paths"
+    fn build_pane(&self) -> Element<'_, Message> {
+        let build = self.progress.nix();
+        let current = match (build.current_drv(), build.current_phase()) {
+            (Some(drv), Some(phase)) => text!("Building {drv} ({phase})"),
+            (Some(drv), None) => text!("Building {drv}"),
+            (None, _) => text("Evaluating and fetching"),
+        };
+        let remaining = text!(
+            "{} builds, {} downloads remaining",
+            build.builds_remaining(),
+            build.downloads_remaining()
         )
-        .dir(&cluster_path)
-        .read()
-        .context("Couldn't build local node")?
-        .into();
-        yield Ok(Message::DiffProgress(3.0));
-
-        let ip_str = ip.to_string();
-        let ssh_config = SshConfig::parse_default_file(ParseRule::STRICT)?;
-        yield Ok(Message::DiffProgress(4.0));
-
-        let params = ssh_config.query(&ip_str);
-        let addr = params
-            .bind_address
-            .and_then(|addr| addr.parse().ok())
-            .unwrap_or_else(|| ip.clone());
-        let port = params.port.unwrap_or(22);
-        let username = params.user.unwrap_or_else(whoami::username).to_string();
+        .size(12);
 
-        let connection = TcpStream::connect((addr, port))?;
-        yield Ok(Message::DiffProgress(5.0));
+        let tail = build.log_tail().fold(column![], |lines, line| {
+            lines.push(text(line).font(Font::MONOSPACE).size(12))
+        });
 
-        let mut session = ssh2::Session::new().expect("Couldn't create ssh session");
-        session.set_tcp_stream(connection);
-        session.handshake()?;
-        yield Ok(Message::DiffProgress(6.0));
-
-        session.userauth_agent(&username)?;
-        yield Ok(Message::DiffProgress(7.0));
-
-        let sftp = session.sftp()?;
-        yield Ok(Message::DiffProgress(8.0));
-
-        let system_drv = sftp.realpath(Path::new("/nix/var/nix/profiles/system/system"))?;
-        yield Ok(Message::DiffProgress(9.0));
-
-        debug!("Copying {system_drv:?} from host");
-
-        drop(session);
-        drop(sftp);
-
-        cmd!("nix-copy-closure", "--from", ip_str, &system_drv)
-            .run()
-            .context("Couldn't download system closure")?;
-        yield Ok(Message::DiffProgress(10.0));
-
-        debug!("Diffing: {system_drv:?} against {new_drv:?}");
+        column![
+            current.size(14),
+            remaining,
+            container(scrollable(tail).anchor_bottom().width(Length::Fill))
+                .height(Length::Fixed(150.))
+                .padding(5)
+                .style(container::dark)
+        ]
+        .spacing(5)
+        .padding(Padding::ZERO.top(5))
+        .into()
+    }
 
-        let diff_out = cmd!("nvd", "--color", "always", "diff", system_drv, new_drv)
-            .read()
-            .context("Couldn't diff the two derivations")?;
+    fn history_pane(&self) -> Element<'_, Message> {
+        let toggle_label = if self.history_expanded {
+            "Hide History"
+        } else {
+            "Show History"
+        };
+        let toggle_btn = button(text(toggle_label).size(12)).on_press(Message::ToggleHistory);
+
+        let history = self.history_expanded.then(|| {
+            let rows = if self.history.is_empty() {
+                column![text("No diffs recorded yet").size(12)]
+            } else {
+                self.history
+                    .iter()
+                    .enumerate()
+                    .fold(column![].spacing(2), |rows, (idx, entry)| {
+                        let status = if entry.diff.up_to_date {
+                            NodeStatus::UpToDate
+                        } else {
+                            NodeStatus::Drifted
+                        };
+                        let rev = entry.flake_rev.as_deref().map(short_rev);
+                        let label = text!(
+                            "{} · {} · {}",
+                            format::timestamp(entry.checked_at),
+                            status.label(),
+                            rev.as_deref().unwrap_or("no git revision")
+                        )
+                        .font(Font::MONOSPACE)
+                        .size(12)
+                        .width(Length::Fill);
+                        let open_btn = button(text("Open").size(12)).on_press_maybe(
+                            (!self.loading_diff).then_some(Message::OpenHistoryEntry(idx)),
+                        );
+                        rows.push(row![label, open_btn].spacing(5))
+                    })
+            };
+            container(scrollable(rows).width(Length::Fill))
+                .height(Length::Fixed(150.))
+                .padding(5)
+                .style(container::dark)
+        });
 
-        yield Ok(Message::DiffResult(Some(diff_out)));
+        column![toggle_btn]
+            .push_maybe(history)
+            .spacing(5)
+            .padding(Padding::ZERO.top(5))
+            .into()
     }
 }
 
-fn fetch_nodes_from_file(path: &Path) -> anyhow::Result<Vec<String>> {
-    if path.ends_with("flake.nix") {
-        fetch_nodes_from_flake(path)
-    } else {
-        bail!("Not a flake.nix file. Cannot fetch nodes");
+/// Appends `line` to `log`, dropping the oldest line once it holds [`MAX_LOG_LINES`].
+pub fn push_log_line(log: &mut VecDeque<String>, line: String) {
+    if log.len() >= MAX_LOG_LINES {
+        log.pop_front();
     }
+    log.push_back(line);
 }
 
-pub fn fetch_nodes_from_flake(flake: &Path) -> anyhow::Result<Vec<String>> {
-    const FLAKE_ARGS: &[&str] = &[
-        "eval",
-        r#".#nixosConfigurations"#,
-        "--json",
-        "--apply",
-        "builtins.attrNames",
-    ];
-    nodes_from_nix_command(flake, &FLAKE_ARGS)
-}
-
-fn run_nix_command_in_dir(file_path: &Path, args: &[&str]) -> anyhow::Result<String> {
-    if !file_path.is_file() {
-        bail!("Nix Cluster path is not a file");
-    }
-
-    let parent = file_path
-        .parent()
-        .context("Cluster path file didn't have a parent folder.")?;
-
-    cmd("nix", args)
-        .dir(parent)
-        .read()
-        .context("Failed to run nix command")
+/// The stderr of the commands run, collapsed into a button showing its length.
+pub fn log_pane<'a, M: Clone + 'a>(
+    log: &'a VecDeque<String>,
+    expanded: bool,
+    on_toggle: M,
+) -> Element<'a, M> {
+    let toggle_label = if expanded {
+        "Hide Command Log"
+    } else {
+        "Show Command Log"
+    };
+    let toggle_btn =
+        button(text!("{toggle_label} ({} lines)", log.len()).size(12)).on_press(on_toggle);
+
+    let log = expanded.then(|| {
+        let lines = log.iter().fold(column![], |lines, line| {
+            lines.push(text(line).font(Font::MONOSPACE).size(12))
+        });
+        container(scrollable(lines).anchor_bottom().width(Length::Fill))
+            .height(Length::Fixed(200.))
+            .padding(5)
+            .style(container::dark)
+    });
+
+    column![toggle_btn]
+        .push_maybe(log)
+        .spacing(5)
+        .padding(Padding::ZERO.top(5))
+        .into()
 }
 
-fn nodes_from_nix_command(file_path: &Path, args: &[&str]) -> anyhow::Result<Vec<String>> {
-    let output = run_nix_command_in_dir(file_path, args)?;
-    nodes_from_json(&output)
+/// `err` with its hint, and its stage and stderr once `expanded`.
+pub fn error_card<'a, M: Clone + 'a>(
+    err: &'a DiffError,
+    expanded: bool,
+    on_toggle: M,
+) -> Element<'a, M> {
+    let title = text(err.to_string())
+        .color(Color::new(1.0, 0.2, 0.2, 1.0))
+        .width(Length::Fill);
+    let toggle_label = if expanded {
+        "Hide Details"
+    } else {
+        "Show Details"
+    };
+    let toggle_btn = button(text(toggle_label).size(12)).on_press(on_toggle);
+    let hint = text!("Hint: {}", err.hint()).size(14);
+
+    let details = expanded.then(|| {
+        let stage = text!("Stage: {}", err.stage.label()).size(12);
+        let stderr = err.stderr().map(|stderr| {
+            container(scrollable(text(stderr).font(Font::MONOSPACE).size(12)))
+                .max_height(250)
+                .width(Length::Fill)
+                .padding(5)
+                .style(container::dark)
+        });
+        column![stage].push_maybe(stderr).spacing(5)
+    });
+
+    container(
+        column![row![title, toggle_btn].spacing(5), hint]
+            .push_maybe(details)
+            .spacing(5),
+    )
+    .padding(10)
+    .width(Length::Fill)
+    .style(container::rounded_box)
+    .into()
 }
 
-fn nodes_from_json(json_output: &str) -> anyhow::Result<Vec<String>> {
-    let json = serde_json::from_str::<serde_json::Value>(json_output)
-        .with_context(|| format!("Couldn't parse json from nix output: {json_output}"))?;
-
-    json.as_array()
-        .map(|nodes| {
-            nodes
-                .iter()
-                .filter_map(|node| node.as_str().map(ToOwned::to_owned))
-                .collect()
-        })
-        .context("Invalid command output. Expected array of nodes.")
+/// Shortens a git revision like git does, keeping a `-dirty` suffix.
+fn short_rev(rev: &str) -> String {
+    let (hash, dirty) = match rev.strip_suffix("-dirty") {
+        Some(hash) => (hash, "-dirty"),
+        None => (rev, ""),
+    };
+    format!("{}{dirty}", hash.get(..7).unwrap_or(hash))
 }
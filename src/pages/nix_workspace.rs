@@ -0,0 +1,198 @@
+use crate::nix::scheduler::{JobKind, JobLimits, Scheduler};
+use crate::pages::Page;
+use crate::pages::nix_cluster::{self, NixClusterView};
+use crate::settings::{ClusterLibrary, Settings};
+use iced::widget::{
+    button, column, container, horizontal_space, row, text, text_input, vertical_rule,
+};
+use iced::{Alignment, Element, Length, Padding, Subscription, Task, time};
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    AddCluster,
+    CloseCluster(usize),
+    Cluster(usize, nix_cluster::Message),
+    JobLimitChanged(JobKind, String),
+    /// Redraws running diffs so their elapsed time and ETA stay current.
+    Tick,
+}
+
+/// Holds several clusters side by side, each with its own nodes and diffs.
+pub struct NixWorkspace {
+    /// Cluster views in display order, tagged with a stable id for routing messages.
+    clusters: Vec<(usize, NixClusterView)>,
+    next_id: usize,
+    library: ClusterLibrary,
+    /// Shared by all clusters, so the limits hold for the whole machine.
+    scheduler: Scheduler,
+    /// Text of the job limit inputs, in the order of [`JobKind::ALL`].
+    job_limit_inputs: [String; 3],
+}
+
+impl Default for NixWorkspace {
+    fn default() -> Self {
+        Self {
+            clusters: vec![(0, NixClusterView::default())],
+            next_id: 1,
+            library: ClusterLibrary::default(),
+            scheduler: Scheduler::default(),
+            job_limit_inputs: Default::default(),
+        }
+    }
+}
+
+impl NixWorkspace {
+    fn set_job_limits(&mut self, limits: JobLimits) {
+        self.job_limit_inputs = JobKind::ALL.map(|kind| limits.get(kind).to_string());
+        self.scheduler.set_limits(limits);
+    }
+
+    fn push_cluster(&mut self, cluster: NixClusterView) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.clusters.push((id, cluster));
+        id
+    }
+}
+
+impl Page for NixWorkspace {
+    type Message = Message;
+
+    fn title(&self) -> &str {
+        "Nix Cluster"
+    }
+
+    fn icon(&self) -> char {
+        '❄'
+    }
+
+    fn update(&mut self, message: Message) -> Task<Message> {
+        match message {
+            Message::AddCluster => {
+                self.push_cluster(NixClusterView::default());
+            }
+            Message::CloseCluster(id) => {
+                // Frees the shared scheduler for the other clusters right away.
+                if let Some((_, cluster)) = self.clusters.iter_mut().find(|(c, _)| *c == id) {
+                    // Cancelling never starts new work, so there's no task to run.
+                    let _ = cluster.update(
+                        nix_cluster::Message::CancelAll,
+                        &mut self.library,
+                        &self.scheduler,
+                    );
+                }
+                self.clusters.retain(|(cluster_id, _)| *cluster_id != id);
+            }
+            Message::Tick => {}
+            Message::JobLimitChanged(kind, input) => {
+                if let Ok(limit @ 1..) = input.trim().parse() {
+                    let mut limits = self.scheduler.limits();
+                    limits.set(kind, limit);
+                    self.scheduler.set_limits(limits);
+                }
+                self.job_limit_inputs[kind as usize] = input;
+            }
+            Message::Cluster(id, msg) => {
+                // Messages of closed clusters are dropped, their tasks have nowhere to report to.
+                if let Some((_, cluster)) = self.clusters.iter_mut().find(|(c, _)| *c == id) {
+                    return cluster
+                        .update(msg, &mut self.library, &self.scheduler)
+                        .map(move |msg| Message::Cluster(id, msg));
+                }
+            }
+        }
+
+        Task::none()
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        let add_btn = button("Add Cluster").on_press(Message::AddCluster);
+        let job_limits = JobKind::ALL.into_iter().fold(
+            row![text("Parallel jobs:")]
+                .spacing(5)
+                .align_y(Alignment::Center),
+            |limits, kind| {
+                let input = text_input("1", &self.job_limit_inputs[kind as usize])
+                    .on_input(move |input| Message::JobLimitChanged(kind, input))
+                    .width(Length::Fixed(40.));
+                limits.push(text(kind.label())).push(input)
+            },
+        );
+        let toolbar = container(
+            row![add_btn, horizontal_space(), job_limits]
+                .spacing(5)
+                .align_y(Alignment::Center),
+        )
+        .padding(Padding::ZERO.bottom(5));
+
+        let clusters = self.clusters.iter().enumerate().fold(
+            row![].spacing(5),
+            |clusters, (position, (id, cluster))| {
+                let id = *id;
+                let close_btn = button("Close")
+                    .on_press_maybe((self.clusters.len() > 1).then_some(Message::CloseCluster(id)));
+                let header = row![text(cluster.display_name()).width(Length::Fill), close_btn];
+                let cluster_view = cluster
+                    .view(&self.library, &self.scheduler)
+                    .map(move |msg| Message::Cluster(id, msg));
+                let clusters = if position > 0 {
+                    clusters.push(vertical_rule(1))
+                } else {
+                    clusters
+                };
+
+                clusters.push(
+                    container(column![header, cluster_view])
+                        .width(Length::FillPortion(1))
+                        .height(Length::Fill),
+                )
+            },
+        );
+
+        column![toolbar, clusters].padding(5).into()
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        if self
+            .clusters
+            .iter()
+            .any(|(_, cluster)| cluster.is_diffing())
+        {
+            time::every(Duration::from_secs(1)).map(|_| Message::Tick)
+        } else {
+            Subscription::none()
+        }
+    }
+
+    fn restore(&mut self, settings: &Settings) -> Task<Message> {
+        self.library = settings.library.clone();
+        self.set_job_limits(settings.jobs);
+        if settings.clusters.is_empty() {
+            return Task::none();
+        }
+
+        self.clusters.clear();
+        let tasks: Vec<_> = settings
+            .clusters
+            .iter()
+            .map(|state| {
+                let (cluster, task) = NixClusterView::restore(state);
+                let id = self.push_cluster(cluster);
+                task.map(move |msg| Message::Cluster(id, msg))
+            })
+            .collect();
+
+        Task::batch(tasks)
+    }
+
+    fn persist(&self, settings: &mut Settings) {
+        settings.library = self.library.clone();
+        settings.jobs = self.scheduler.limits();
+        settings.clusters = self
+            .clusters
+            .iter()
+            .map(|(_, cluster)| cluster.persist())
+            .collect();
+    }
+}
@@ -5,12 +5,13 @@ use std::net::IpAddr;
 use std::process::{Child, Command, Stdio};
 use std::str::FromStr;
 use futures::StreamExt;
-use iced::{Color, Element, Length, Padding};
+use iced::{Color, Element, Length, Padding, Task};
 use iced::widget::{button, container, row, scrollable, text, text_input, Column, Space, TextEditor};
 use iced::widget::text_editor::Content;
 use iced_futures::{subscription, BoxStream, Subscription};
 use iced_futures::subscription::{EventStream, Hasher};
 use crate::PingProc;
+use crate::pages::Page;
 
 impl subscription::Recipe for PingProc {
     type Output = Message;
@@ -77,8 +78,18 @@ pub struct PingPage {
     active_ping: Option<Child>,
 }
 
-impl PingPage {
-    pub fn update(&mut self, message: Message) {
+impl Page for PingPage {
+    type Message = Message;
+
+    fn title(&self) -> &str {
+        "Ping"
+    }
+
+    fn icon(&self) -> char {
+        '◉'
+    }
+
+    fn update(&mut self, message: Message) -> Task<Message> {
         match message {
             Message::CheckIp => {
                 let target = match IpAddr::from_str(&self.ip_input) {
@@ -88,7 +99,7 @@ impl PingPage {
                     }
                     Err(e) => {
                         self.ping_error = Some(e.to_string());
-                        return;
+                        return Task::none();
                     }
                 };
 
@@ -112,9 +123,11 @@ impl PingPage {
                 self.active_ping = None;
             }
         }
+
+        Task::none()
     }
 
-    pub fn view(&self) -> Element<Message> {
+    fn view(&self) -> Element<'_, Message> {
         let ping_header = text("Ping Scan").width(Length::Fill).center();
         let ip_input = text_input("IP Address", &self.ip_input).on_input(Message::UpdateIP);
         let check_error = self.ping_error.as_ref().map(|err| {
@@ -154,13 +167,13 @@ impl PingPage {
         container(row![left, right]).into()
     }
 
-    pub fn subscription(&self) -> Subscription<Message> {
+    fn subscription(&self) -> Subscription<Message> {
         let Some(log_stream) = &self.target else {
             return Subscription::none();
         };
 
         subscription::from_recipe(PingProc {
-            target: log_stream.clone(),
+            target: *log_stream,
         })
     }
 }
\ No newline at end of file
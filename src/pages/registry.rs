@@ -0,0 +1,161 @@
+use crate::pages::Page;
+use crate::settings::Settings;
+use iced::{Element, Task};
+use iced_futures::Subscription;
+use log::error;
+use std::any::{Any, type_name};
+use std::fmt::{Debug, Formatter};
+
+/// Type-erased message of any registered page.
+pub struct PageMessage(Box<dyn AnyMessage>);
+
+impl PageMessage {
+    pub fn new<M: Debug + Clone + Send + 'static>(message: M) -> Self {
+        Self(Box::new(message))
+    }
+}
+
+impl Debug for PageMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Clone for PageMessage {
+    fn clone(&self) -> Self {
+        Self(self.0.clone_box())
+    }
+}
+
+trait AnyMessage: Debug + Send {
+    fn clone_box(&self) -> Box<dyn AnyMessage>;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<M: Debug + Clone + Send + 'static> AnyMessage for M {
+    fn clone_box(&self) -> Box<dyn AnyMessage> {
+        Box::new(self.clone())
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+trait AnyPage {
+    fn title(&self) -> &str;
+    fn icon(&self) -> char;
+    fn update(&mut self, message: PageMessage) -> Task<PageMessage>;
+    fn view(&self) -> Element<'_, PageMessage>;
+    fn subscription(&self) -> Subscription<PageMessage>;
+    fn restore(&mut self, settings: &Settings) -> Task<PageMessage>;
+    fn persist(&self, settings: &mut Settings);
+}
+
+impl<P: Page> AnyPage for P {
+    fn title(&self) -> &str {
+        Page::title(self)
+    }
+
+    fn icon(&self) -> char {
+        Page::icon(self)
+    }
+
+    fn update(&mut self, message: PageMessage) -> Task<PageMessage> {
+        match message.0.into_any().downcast::<P::Message>() {
+            Ok(message) => Page::update(self, *message).map(PageMessage::new),
+            Err(_) => {
+                error!(
+                    "Page {} received a message of a foreign type",
+                    type_name::<P>()
+                );
+                Task::none()
+            }
+        }
+    }
+
+    fn view(&self) -> Element<'_, PageMessage> {
+        Page::view(self).map(PageMessage::new)
+    }
+
+    fn subscription(&self) -> Subscription<PageMessage> {
+        Page::subscription(self).map(PageMessage::new)
+    }
+
+    fn restore(&mut self, settings: &Settings) -> Task<PageMessage> {
+        Page::restore(self, settings).map(PageMessage::new)
+    }
+
+    fn persist(&self, settings: &mut Settings) {
+        Page::persist(self, settings)
+    }
+}
+
+/// Holds every page of the app in sidebar order, together with the currently visible one.
+#[derive(Default)]
+pub struct PageRegistry {
+    pages: Vec<Box<dyn AnyPage>>,
+    active: usize,
+}
+
+impl PageRegistry {
+    pub fn register(mut self, page: impl Page + 'static) -> Self {
+        self.pages.push(Box::new(page));
+        self
+    }
+
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    pub fn set_active(&mut self, idx: usize) {
+        if idx < self.pages.len() {
+            self.active = idx;
+        }
+    }
+
+    /// Icon and title of every registered page, in registration order.
+    pub fn entries(&self) -> impl Iterator<Item = (usize, char, &str)> {
+        self.pages
+            .iter()
+            .enumerate()
+            .map(|(idx, page)| (idx, page.icon(), page.title()))
+    }
+
+    pub fn update(&mut self, idx: usize, message: PageMessage) -> Task<PageMessage> {
+        match self.pages.get_mut(idx) {
+            Some(page) => page.update(message),
+            None => Task::none(),
+        }
+    }
+
+    pub fn view(&self) -> Option<Element<'_, PageMessage>> {
+        self.pages.get(self.active).map(|page| page.view())
+    }
+
+    /// Restores every page, returning their startup tasks tagged with the page index.
+    pub fn restore(&mut self, settings: &Settings) -> Task<(usize, PageMessage)> {
+        Task::batch(
+            self.pages
+                .iter_mut()
+                .enumerate()
+                .map(|(idx, page)| page.restore(settings).map(move |msg| (idx, msg))),
+        )
+    }
+
+    pub fn persist(&self, settings: &mut Settings) {
+        for page in &self.pages {
+            page.persist(settings);
+        }
+    }
+
+    /// Subscriptions of all pages, tagged with their page index.
+    pub fn subscription(&self) -> Subscription<(usize, PageMessage)> {
+        Subscription::batch(
+            self.pages
+                .iter()
+                .enumerate()
+                .map(|(idx, page)| page.subscription().with(idx)),
+        )
+    }
+}
@@ -0,0 +1,126 @@
+use crate::nix::ClusterConfig;
+use crate::nix::cache::DiffKey;
+use crate::nix::scheduler::JobLimits;
+use anyhow::Context;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Everything CheckIT remembers between launches.
+///
+/// Stored as TOML in `$XDG_CONFIG_HOME/checkit/settings.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub window: WindowGeometry,
+    /// Clusters open in the workspace, in display order.
+    pub clusters: Vec<ClusterState>,
+    /// Parallelism of the diffs of all clusters.
+    pub jobs: JobLimits,
+    #[serde(flatten)]
+    pub library: ClusterLibrary,
+}
+
+/// Profiles and recently opened clusters, shared by all clusters of the workspace.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterLibrary {
+    pub profiles: Vec<ClusterProfile>,
+    /// Most recently opened cluster paths, newest first.
+    pub recent_clusters: Vec<PathBuf>,
+}
+
+impl ClusterLibrary {
+    pub const MAX_RECENT_CLUSTERS: usize = 10;
+
+    pub fn remember_recent_cluster(&mut self, path: PathBuf) {
+        self.recent_clusters.retain(|recent| *recent != path);
+        self.recent_clusters.insert(0, path);
+        self.recent_clusters.truncate(Self::MAX_RECENT_CLUSTERS);
+    }
+}
+
+/// A named cluster configuration the user can switch to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterProfile {
+    pub name: String,
+    #[serde(flatten)]
+    pub config: ClusterConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowGeometry {
+    pub width: f32,
+    pub height: f32,
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+impl Default for WindowGeometry {
+    fn default() -> Self {
+        Self {
+            width: 1024.,
+            height: 768.,
+            x: None,
+            y: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterState {
+    #[serde(flatten)]
+    pub config: ClusterConfig,
+    /// Name of the profile the cluster was loaded from or saved as.
+    pub profile_name: String,
+    /// IP attributes of nodes that differ from the cluster wide `ip_attr`.
+    pub node_ip_attrs: BTreeMap<String, String>,
+    pub selected_node: Option<String>,
+    /// Systems compared by each node's last diff, to show it again from the diff cache.
+    pub last_diffs: BTreeMap<String, DiffKey>,
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("checkit").join("settings.toml"))
+    }
+
+    /// Loads the settings from disk, falling back to the defaults if they're missing or broken.
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            warn!("No config directory available. Settings won't be restored.");
+            return Self::default();
+        };
+
+        if !path.exists() {
+            return Self::default();
+        }
+
+        let settings = fs::read_to_string(&path)
+            .with_context(|| format!("Couldn't read {}", path.display()))
+            .and_then(|raw| toml::from_str(&raw).context("Couldn't parse settings"));
+
+        match settings {
+            Ok(settings) => settings,
+            Err(err) => {
+                error!("Failed to load settings: {err:?}");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = Self::path().context("No config directory available")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Couldn't create {}", dir.display()))?;
+        }
+
+        let raw = toml::to_string_pretty(self).context("Couldn't serialize settings")?;
+        fs::write(&path, raw).with_context(|| format!("Couldn't write {}", path.display()))
+    }
+}
@@ -31,20 +31,18 @@ pub fn ansi_to_spans(ansi_text: &str) -> Vec<(&str, Option<Color>)> {
     for ansi in ansi_text.ansi_parse() {
         match ansi {
             Output::TextBlock(text) => spans.push((text, color)),
-            Output::Escape(esc) => match esc {
-                AnsiSequence::SetGraphicsMode(mode) => {
-                    for param in mode {
-                        match param {
-                            0 => color = None,
-                            30..=37 => color = Some(ansi_color_from_code(param)),
-                            90..=97 => color = Some(ansi_color_from_code(param)),
-                            39 => color = None,
-                            _ => {}
-                        }
+            Output::Escape(AnsiSequence::SetGraphicsMode(mode)) => {
+                for param in mode {
+                    match param {
+                        0 => color = None,
+                        30..=37 => color = Some(ansi_color_from_code(param)),
+                        90..=97 => color = Some(ansi_color_from_code(param)),
+                        39 => color = None,
+                        _ => {}
                     }
                 }
-                _ => {}
-            },
+            }
+            Output::Escape(_) => {}
         }
     }
 
@@ -57,3 +55,10 @@ pub fn make_spans<'a, Link>(spans: &[(&'a str, Option<Color>)]) -> Vec<Span<'a,
         .map(|(text, color)| span(*text).color_maybe(*color))
         .collect()
 }
+
+pub fn strip_ansi(ansi_text: &str) -> String {
+    ansi_to_spans(ansi_text)
+        .into_iter()
+        .map(|(text, _)| text)
+        .collect()
+}
@@ -1 +1,2 @@
-pub mod ansi_to_rich;
\ No newline at end of file
+pub mod ansi_to_rich;
+pub mod format;